@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Storage for the contents of cached files. Inodes and directory descriptors always live
+// under data_dir, only file data goes through this.
+pub(crate) trait ContentCache: Send + Sync {
+    // Copy the cached bytes at `offset` into `buf` and return how many there were
+    fn read_range(&self, inode: u64, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    // Write `data` at `offset` of an existing entry, a gap before it reads as zeros
+    fn write_range(&self, inode: u64, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    // Set the cached length, creating an empty entry if there is none
+    fn truncate(&self, inode: u64, size: u64) -> io::Result<()>;
+
+    fn evict(&self, inode: u64) -> io::Result<()>;
+
+    // Length of the cached contents, None when nothing is cached
+    fn cached_len(&self, inode: u64) -> Option<u64>;
+
+    // 512 byte blocks holding the contents, None when nothing is cached
+    fn allocated_blocks(&self, inode: u64) -> Option<u64>;
+
+    // The file backing an entry, for downloads, dedup hard links and kernel-side copies.
+    // None when the cache doesn't keep contents in files.
+    fn file_path(&self, inode: u64) -> Option<PathBuf>;
+
+    // Bytes still free for new contents, None when the cache has no fixed budget
+    fn available_space(&self) -> Option<u64>;
+}
+
+// One file per inode under "$data_dir/contents"
+pub(crate) struct FileCache {
+    dir: PathBuf,
+}
+
+impl FileCache {
+    pub fn new(data_dir: &str) -> FileCache {
+        FileCache {
+            dir: Path::new(data_dir).join("contents"),
+        }
+    }
+
+    fn path(&self, inode: u64) -> PathBuf {
+        self.dir.join(inode.to_string())
+    }
+}
+
+impl ContentCache for FileCache {
+    fn read_range(&self, inode: u64, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let file = File::open(self.path(inode))?;
+        let available = file.metadata()?.len().saturating_sub(offset).min(buf.len() as u64) as usize;
+        file.read_exact_at(&mut buf[..available], offset)?;
+        Ok(available)
+    }
+
+    fn write_range(&self, inode: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(self.path(inode))?;
+        file.write_all_at(data, offset)
+    }
+
+    fn truncate(&self, inode: u64, size: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(self.path(inode))?;
+        file.set_len(size)
+    }
+
+    fn evict(&self, inode: u64) -> io::Result<()> {
+        match fs::remove_file(self.path(inode)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn cached_len(&self, inode: u64) -> Option<u64> {
+        fs::metadata(self.path(inode)).ok().map(|metadata| metadata.len())
+    }
+
+    // Sparse and partially written files report what they really use on disk
+    fn allocated_blocks(&self, inode: u64) -> Option<u64> {
+        fs::metadata(self.path(inode)).ok().map(|metadata| metadata.blocks())
+    }
+
+    fn file_path(&self, inode: u64) -> Option<PathBuf> {
+        Some(self.path(inode))
+    }
+
+    // Free space for unprivileged users on the filesystem holding the cache directory
+    fn available_space(&self) -> Option<u64> {
+        let stats = statvfs(&self.dir).ok()?;
+        Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+    }
+}
+
+pub(crate) fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stats)
+}
+
+// Contents kept in RAM, for ephemeral mounts that trade memory for speed. Nothing survives
+// a remount, files are downloaded again on their first open.
+pub(crate) struct MemoryCache {
+    entries: Mutex<HashMap<u64, Vec<u8>>>,
+}
+
+impl MemoryCache {
+    pub fn new() -> MemoryCache {
+        MemoryCache {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ContentCache for MemoryCache {
+    fn read_range(&self, inode: u64, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let entries = self.entries.lock().unwrap();
+        let content = entries.get(&inode).ok_or(io::ErrorKind::NotFound)?;
+        let start = offset.min(content.len() as u64) as usize;
+        let available = (content.len() - start).min(buf.len());
+        buf[..available].copy_from_slice(&content[start..start + available]);
+        Ok(available)
+    }
+
+    fn write_range(&self, inode: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let content = entries.get_mut(&inode).ok_or(io::ErrorKind::NotFound)?;
+        let start = usize::try_from(offset).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let end = start.checked_add(data.len()).ok_or(io::ErrorKind::InvalidInput)?;
+        if end > content.len() {
+            content.resize(end, 0);
+        }
+        content[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&self, inode: u64, size: u64) -> io::Result<()> {
+        let size = usize::try_from(size).map_err(|_| io::ErrorKind::InvalidInput)?;
+        self.entries.lock().unwrap().entry(inode).or_default().resize(size, 0);
+        Ok(())
+    }
+
+    fn evict(&self, inode: u64) -> io::Result<()> {
+        self.entries.lock().unwrap().remove(&inode);
+        Ok(())
+    }
+
+    fn cached_len(&self, inode: u64) -> Option<u64> {
+        self.entries.lock().unwrap().get(&inode).map(|content| content.len() as u64)
+    }
+
+    fn allocated_blocks(&self, inode: u64) -> Option<u64> {
+        self.cached_len(inode).map(|len| len / 512 + (len % 512 != 0) as u64)
+    }
+
+    fn file_path(&self, _inode: u64) -> Option<PathBuf> {
+        None
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same behaviour is expected from every cache
+    fn check_contents(cache: &dyn ContentCache) {
+        let mut buf = [0xff; 16];
+        assert_eq!(cache.cached_len(1), None);
+        assert!(cache.read_range(1, 0, &mut buf).is_err());
+        assert!(cache.write_range(1, 0, b"abc").is_err());
+
+        cache.truncate(1, 0).unwrap();
+        assert_eq!(cache.cached_len(1), Some(0));
+        cache.write_range(1, 0, b"hello").unwrap();
+        // A write past the end leaves a gap of zeros
+        cache.write_range(1, 8, b"world").unwrap();
+        assert_eq!(cache.cached_len(1), Some(13));
+        assert_eq!(cache.read_range(1, 0, &mut buf).unwrap(), 13);
+        assert_eq!(&buf[..13], b"hello\0\0\0world");
+        // Reads stop at the end of the contents
+        assert_eq!(cache.read_range(1, 10, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"rld");
+        assert_eq!(cache.read_range(1, 20, &mut buf).unwrap(), 0);
+
+        cache.truncate(1, 4).unwrap();
+        assert_eq!(cache.cached_len(1), Some(4));
+        assert_eq!(cache.read_range(1, 0, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"hell");
+        cache.truncate(1, 6).unwrap();
+        assert_eq!(cache.read_range(1, 0, &mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"hell\0\0");
+
+        // Entries are independent of each other
+        cache.truncate(2, 3).unwrap();
+        assert_eq!(cache.cached_len(2), Some(3));
+        cache.evict(1).unwrap();
+        assert_eq!(cache.cached_len(1), None);
+        assert_eq!(cache.cached_len(2), Some(3));
+        // Evicting what isn't cached is fine
+        cache.evict(1).unwrap();
+    }
+
+    #[test]
+    fn file_cache_contents() {
+        let data_dir = std::env::temp_dir().join(format!("rusty-s3fs-cache-{}", std::process::id()));
+        fs::create_dir_all(data_dir.join("contents")).unwrap();
+        let cache = FileCache::new(data_dir.to_str().unwrap());
+        check_contents(&cache);
+        assert!(cache.file_path(2).unwrap().exists());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn memory_cache_contents() {
+        let cache = MemoryCache::new();
+        check_contents(&cache);
+        assert_eq!(cache.file_path(2), None);
+        assert_eq!(cache.allocated_blocks(2), Some(1));
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use log::{error, info, LevelFilter};
+use tokio::signal::unix::{signal, SignalKind};
+
+// Settings chosen at mount time, changing them requires a remount
+const FIXED_KEYS: [&str; 3] = ["bucket-name", "data-dir", "mount-point"];
+
+// Size limits the filesystem looks up every time it enforces them, so a reload applies from
+// the next write or download on. u64::MAX stands for no limit.
+pub(crate) struct Limits {
+    cache_size: AtomicU64,
+    writeback_high_water: AtomicU64,
+    writeback_low_water: AtomicU64,
+}
+
+impl Limits {
+    pub fn new(cache_size: Option<u64>, writeback_high_water: Option<u64>, writeback_low_water: u64) -> Limits {
+        Limits {
+            cache_size: AtomicU64::new(cache_size.unwrap_or(u64::MAX)),
+            writeback_high_water: AtomicU64::new(writeback_high_water.unwrap_or(u64::MAX)),
+            writeback_low_water: AtomicU64::new(writeback_low_water),
+        }
+    }
+
+    pub fn cache_size(&self) -> Option<u64> {
+        Some(self.cache_size.load(Ordering::SeqCst)).filter(|&limit| limit != u64::MAX)
+    }
+
+    pub fn writeback_high_water(&self) -> Option<u64> {
+        Some(self.writeback_high_water.load(Ordering::SeqCst)).filter(|&limit| limit != u64::MAX)
+    }
+
+    pub fn writeback_low_water(&self) -> u64 {
+        self.writeback_low_water.load(Ordering::SeqCst)
+    }
+}
+
+// The subset of settings that can be changed on a live mount
+#[derive(Clone, PartialEq)]
+struct Tunables {
+    log_level: LevelFilter,
+    cache_size: Option<u64>,
+    writeback_high_water: Option<u64>,
+    writeback_low_water: u64,
+}
+
+impl Tunables {
+    fn current(limits: &Limits) -> Tunables {
+        Tunables {
+            log_level: log::max_level(),
+            cache_size: limits.cache_size(),
+            writeback_high_water: limits.writeback_high_water(),
+            writeback_low_water: limits.writeback_low_water(),
+        }
+    }
+
+    fn apply(&self, limits: &Limits) {
+        // The logger's own RUST_LOG filter still applies, so this can only lower verbosity below it
+        log::set_max_level(self.log_level);
+        limits.cache_size.store(self.cache_size.unwrap_or(u64::MAX), Ordering::SeqCst);
+        limits.writeback_high_water.store(self.writeback_high_water.unwrap_or(u64::MAX), Ordering::SeqCst);
+        limits.writeback_low_water.store(self.writeback_low_water, Ordering::SeqCst);
+    }
+}
+
+// Sizes are plain byte counts, `none` lifts the limit
+fn parse_limit(line: usize, key: &str, value: &str) -> Result<Option<u64>, String> {
+    if value == "none" {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("line {}: invalid {} {}", line, key, value))
+}
+
+// Parse a config file of `key = value` lines on top of the current tunables.
+// Any invalid line rejects the whole file, so a reload is applied entirely or not at all.
+fn parse_config(path: &str, current: &Tunables) -> Result<Tunables, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read config file {}: {}", path, e))?;
+    let mut tunables = current.clone();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(format!("line {}: expected `key = value`", index + 1)),
+        };
+        if FIXED_KEYS.contains(&key) {
+            return Err(format!(
+                "line {}: {} is fixed for the lifetime of the mount, remount to change it",
+                index + 1,
+                key
+            ));
+        }
+        match key {
+            "log-level" => {
+                tunables.log_level = LevelFilter::from_str(value)
+                    .map_err(|_| format!("line {}: invalid log level {}", index + 1, value))?;
+            }
+            "cache-size" => tunables.cache_size = parse_limit(index + 1, key, value)?,
+            "writeback-high-water" => tunables.writeback_high_water = parse_limit(index + 1, key, value)?,
+            "writeback-low-water" => {
+                tunables.writeback_low_water = value
+                    .parse()
+                    .map_err(|_| format!("line {}: invalid {} {}", index + 1, key, value))?;
+            }
+            _ => return Err(format!("line {}: unknown setting {}", index + 1, key)),
+        }
+    }
+    if matches!(tunables.writeback_high_water, Some(high_water) if tunables.writeback_low_water > high_water) {
+        return Err("writeback-low-water must not be above writeback-high-water".to_string());
+    }
+    Ok(tunables)
+}
+
+fn describe_limit(limit: Option<u64>) -> String {
+    limit.map_or_else(|| "none".to_string(), |limit| limit.to_string())
+}
+
+// Re-read the config file and apply it, logging every setting that changed
+pub(crate) fn reload(path: &str, limits: &Limits) -> Result<(), String> {
+    let current = Tunables::current(limits);
+    let updated = parse_config(path, &current)?;
+    if updated == current {
+        info!("config reload: no changes in {}", path);
+        return Ok(());
+    }
+    if updated.log_level != current.log_level {
+        info!("config reload: log-level {} -> {}", current.log_level, updated.log_level);
+    }
+    if updated.cache_size != current.cache_size {
+        info!(
+            "config reload: cache-size {} -> {}",
+            describe_limit(current.cache_size),
+            describe_limit(updated.cache_size)
+        );
+    }
+    if updated.writeback_high_water != current.writeback_high_water {
+        info!(
+            "config reload: writeback-high-water {} -> {}",
+            describe_limit(current.writeback_high_water),
+            describe_limit(updated.writeback_high_water)
+        );
+    }
+    if updated.writeback_low_water != current.writeback_low_water {
+        info!(
+            "config reload: writeback-low-water {} -> {}",
+            current.writeback_low_water, updated.writeback_low_water
+        );
+    }
+    updated.apply(limits);
+    Ok(())
+}
+
+// Reload the config file every time the process receives SIGHUP
+pub(crate) fn watch_sighup(path: String, limits: Arc<Limits>) {
+    thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut hangup = signal(SignalKind::hangup()).unwrap();
+            while hangup.recv().await.is_some() {
+                if let Err(e) = reload(&path, &limits) {
+                    error!("config reload rejected: {}", e);
+                }
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str, text: &str, current: &Tunables) -> Result<Tunables, String> {
+        let path = std::env::temp_dir().join(format!("rusty-s3fs-config-{}-{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        let parsed = parse_config(path.to_str().unwrap(), current);
+        let _ = fs::remove_file(&path);
+        parsed
+    }
+
+    fn defaults() -> Tunables {
+        Tunables {
+            log_level: LevelFilter::Info,
+            cache_size: None,
+            writeback_high_water: Some(1000),
+            writeback_low_water: 500,
+        }
+    }
+
+    #[test]
+    fn limits_are_reloadable() {
+        let parsed = parse("limits", "cache-size = 4096\nwriteback-high-water = none\n", &defaults()).unwrap();
+        assert_eq!(parsed.cache_size, Some(4096));
+        assert_eq!(parsed.writeback_high_water, None);
+        assert_eq!(parsed.writeback_low_water, 500);
+    }
+
+    #[test]
+    fn low_water_above_high_water_is_rejected() {
+        assert!(parse("water", "writeback-high-water = 400\n", &defaults()).is_err());
+        assert!(parse("water-both", "writeback-high-water = 400\nwriteback-low-water = 100\n", &defaults()).is_ok());
+    }
+
+    #[test]
+    fn limits_round_trip_through_the_atomics() {
+        let limits = Limits::new(None, Some(1000), 500);
+        let mut tunables = Tunables::current(&limits);
+        assert_eq!(tunables.cache_size, None);
+        tunables.cache_size = Some(1);
+        tunables.writeback_high_water = None;
+        tunables.apply(&limits);
+        assert_eq!(limits.cache_size(), Some(1));
+        assert_eq!(limits.writeback_high_water(), None);
+    }
+}
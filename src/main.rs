@@ -1,13 +1,30 @@
+mod cache;
+mod config;
 mod s3fs;
 mod s3util;
+mod transform;
 use fuser;
 use clap::{crate_version, Arg, Command};
 use std::env;
 use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 use fuser::MountOption;
-use log::error;
-use crate::s3fs::S3FS;
-use crate::s3util::GcsWorker;
+use log::{error, warn};
+use crate::cache::{ContentCache, FileCache, MemoryCache};
+use crate::config::Limits;
+use crate::s3fs::{
+    watch_sigusr2, watch_upload_queue, FsOptions, FsyncOnClose, HardlinkPolicy, OversizePolicy, ReaddirOrder, S3FS,
+};
+use crate::s3util::{load_ca_cert, DownloadOptions, GcsWorker, HttpOptions, S3Worker, StorageBackend, WorkerError, WorkerResult};
+use crate::transform::{PrefixTransform, TransformedBackend};
+
+// Key stat'ed by --fail-fast-on-auth-error, it doesn't need to exist
+const AUTH_PROBE_KEY: &str = ".s3fs-auth-probe";
+// How long --fsync-on-close never leaves files queued when --writeback-delay isn't given
+const NEVER_ON_CLOSE_WRITEBACK_DELAY: Duration = Duration::from_secs(30);
 
 
 
@@ -49,6 +66,360 @@ fn main() {
                 .long("allow-root")
                 .help("Allow root user to access filesystem"),
         )
+        .arg(
+            Arg::new("mount-option")
+                .long("mount-option")
+                .short('o')
+                .value_name("OPTION[,OPTION...]")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Pass mount options such as ro, nosuid or fsname=NAME to the mount"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Config file with runtime tunables, re-read on SIGHUP"),
+        )
+        .arg(
+            Arg::new("http-max-idle-per-host")
+                .long("http-max-idle-per-host")
+                .value_name("COUNT")
+                .default_value("32")
+                .help("Maximum idle backend connections kept open for reuse"),
+        )
+        .arg(
+            Arg::new("http-idle-timeout")
+                .long("http-idle-timeout")
+                .value_name("SECONDS")
+                .default_value("90")
+                .help("Keep idle backend connections alive for this long"),
+        )
+        .arg(
+            Arg::new("tcp-keepalive")
+                .long("tcp-keepalive")
+                .value_name("SECONDS")
+                .default_value("60")
+                .help("Send TCP keepalive probes on backend connections this often"),
+        )
+        .arg(
+            Arg::new("connect-timeout")
+                .long("connect-timeout")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .help("Give up connecting to the backend after this long, transfers themselves aren't limited"),
+        )
+        .arg(
+            Arg::new("http2")
+                .long("http2")
+                .help("Talk to the backend over HTTP/2 only"),
+        )
+        .arg(
+            Arg::new("endpoint-ca-cert")
+                .long("endpoint-ca-cert")
+                .value_name("PEM_FILE")
+                .takes_value(true)
+                .help("Trust this CA certificate when connecting to the backend endpoint"),
+        )
+        .arg(
+            Arg::new("insecure-skip-verify")
+                .long("insecure-skip-verify")
+                .help("Skip TLS certificate verification, for testing only"),
+        )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .value_name("SERVICE")
+                .possible_values(["gcs", "s3"])
+                .default_value("gcs")
+                .help("Object storage service hosting the bucket"),
+        )
+        .arg(
+            Arg::new("download-block-size")
+                .long("download-block-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Size of the ranges objects are downloaded in, smaller objects take a single request"),
+        )
+        .arg(
+            Arg::new("download-threads")
+                .long("download-threads")
+                .value_name("COUNT")
+                .takes_value(true)
+                .help("Ranges of one object downloaded at the same time"),
+        )
+        .arg(
+            Arg::new("upload-part-size")
+                .long("upload-part-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Size of the parts larger files are uploaded in, smaller files take a single request"),
+        )
+        .arg(
+            Arg::new("max-upload-parts")
+                .long("max-upload-parts")
+                .value_name("COUNT")
+                .takes_value(true)
+                .help("Parts one upload may have, larger files are uploaded in larger parts"),
+        )
+        .arg(
+            Arg::new("readahead-blocks")
+                .long("readahead-blocks")
+                .value_name("COUNT")
+                .takes_value(true)
+                .help("Fetch files opened for reading block by block, prefetching COUNT blocks ahead of sequential reads"),
+        )
+        .arg(
+            Arg::new("prefetch-head-bytes")
+                .long("prefetch-head-bytes")
+                .value_name("BYTES")
+                .takes_value(true)
+                .requires("readahead-blocks")
+                .help("Fetch the blocks holding the first BYTES of a file read block by block when it is opened"),
+        )
+        .arg(
+            Arg::new("prefetch-tail-bytes")
+                .long("prefetch-tail-bytes")
+                .value_name("BYTES")
+                .takes_value(true)
+                .requires("readahead-blocks")
+                .help("Fetch the blocks holding the last BYTES of a file read block by block when it is opened"),
+        )
+        .arg(
+            Arg::new("stream-reads")
+                .long("stream-reads")
+                .conflicts_with("readahead-blocks")
+                .help("Stream files opened for reading from the bucket without caching them"),
+        )
+        .arg(
+            Arg::new("object-prefix-transform")
+                .long("object-prefix-transform")
+                .value_name("BACKEND=DISPLAY")
+                .takes_value(true)
+                .help("Present keys starting with the BACKEND template, e.g. logs/{yyyy}/{mm}/{dd}/, under the DISPLAY one, e.g. logs/{yyyy}-{mm}-{dd}/"),
+        )
+        .arg(
+            Arg::new("gcs-endpoint")
+                .long("gcs-endpoint")
+                .value_name("URL")
+                .takes_value(true)
+                .help("Send backend requests to this endpoint instead of Google Cloud Storage"),
+        )
+        .arg(
+            Arg::new("gcs-credential")
+                .long("gcs-credential")
+                .value_name("JSON_FILE")
+                .takes_value(true)
+                .help("Authenticate with this service account key instead of the default credentials"),
+        )
+        .arg(
+            Arg::new("gcs-scope")
+                .long("gcs-scope")
+                .value_name("SCOPE")
+                .takes_value(true)
+                .help("OAuth scope requested for the backend credentials"),
+        )
+        .arg(
+            Arg::new("s3-endpoint")
+                .long("s3-endpoint")
+                .value_name("URL")
+                .takes_value(true)
+                .help("Send S3 requests to this endpoint instead of AWS"),
+        )
+        .arg(
+            Arg::new("s3-region")
+                .long("s3-region")
+                .value_name("REGION")
+                .takes_value(true)
+                .help("Region of the S3 bucket"),
+        )
+        .arg(
+            Arg::new("show-meta-files")
+                .long("show-meta-files")
+                .help("Show each file's backend metadata as a read-only <name>.s3meta JSON file"),
+        )
+        .arg(
+            Arg::new("immutable-glob")
+                .long("immutable-glob")
+                .value_name("GLOB")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Serve cached files whose key matches GLOB without checking the backend for changes"),
+        )
+        .arg(
+            Arg::new("discovery-depth")
+                .long("discovery-depth")
+                .value_name("LEVELS")
+                .takes_value(true)
+                .help("List this many directory levels at mount time, or \"all\" to walk the whole bucket. By default directories are listed on first access"),
+        )
+        .arg(
+            Arg::new("max-read")
+                .long("max-read")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Let the kernel read up to this many bytes per request"),
+        )
+        .arg(
+            Arg::new("max-write")
+                .long("max-write")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Let the kernel write up to this many bytes per request"),
+        )
+        .arg(
+            Arg::new("readdir-order")
+                .long("readdir-order")
+                .value_name("ORDER")
+                .possible_values(["name", "mtime", "size"])
+                .default_value("name")
+                .help("List directories by name, newest first or largest first"),
+        )
+        .arg(
+            Arg::new("oversize-policy")
+                .long("oversize-policy")
+                .value_name("POLICY")
+                .possible_values(["stream", "error"])
+                .default_value("stream")
+                .help("Stream files too large for the cache from the bucket, or refuse to open them"),
+        )
+        .arg(
+            Arg::new("hardlink-upload")
+                .long("hardlink-upload")
+                .value_name("POLICY")
+                .possible_values(["canonical", "all"])
+                .default_value("canonical")
+                .help("Upload files with several hard links to their first key and copy it to the others, or to every key"),
+        )
+        .arg(
+            Arg::new("kernel-writeback")
+                .long("kernel-writeback")
+                .help("Let the kernel buffer writes in its page cache and send them coalesced"),
+        )
+        .arg(
+            Arg::new("serve-cached")
+                .long("serve-cached")
+                .help("Open cached files for reading without checking the backend for a newer version"),
+        )
+        .arg(
+            Arg::new("single-object")
+                .long("single-object")
+                .value_name("KEY")
+                .takes_value(true)
+                .conflicts_with("discovery-depth")
+                .help("Mount only this object, as the single file in the mount point"),
+        )
+        .arg(
+            Arg::new("fail-fast-on-auth-error")
+                .long("fail-fast-on-auth-error")
+                .help("Check the credentials against the bucket before mounting and exit if they are refused"),
+        )
+        .arg(
+            Arg::new("max-symlink-depth")
+                .long("max-symlink-depth")
+                .value_name("COUNT")
+                .default_value("40")
+                .help("Symbolic links followed through the mount before readlink fails with ELOOP"),
+        )
+        .arg(
+            Arg::new("keep-marker")
+                .long("keep-marker")
+                .value_name("NAME")
+                .default_value(".keep")
+                .help("Object kept in each directory on backends that refuse zero-byte directory markers"),
+        )
+        .arg(
+            Arg::new("append-segments")
+                .long("append-segments")
+                .conflicts_with("single-object")
+                .help("Never overwrite objects, upload what was appended to a file as a new segment"),
+        )
+        .arg(
+            Arg::new("bsd-groups")
+                .long("bsd-groups")
+                .help("Give new files and directories the group of their parent directory"),
+        )
+        .arg(
+            Arg::new("writeback-delay")
+                .long("writeback-delay")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .help("Upload closed files this long after they were first closed with changes, instead of right away"),
+        )
+        .arg(
+            Arg::new("shutdown-grace")
+                .long("shutdown-grace")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .help("Stop uploading files with local changes this long into the unmount, leaving the rest for the next mount"),
+        )
+        .arg(
+            Arg::new("fsync-on-close")
+                .long("fsync-on-close")
+                .value_name("POLICY")
+                .possible_values(["always", "never", "dirty-only"])
+                .default_value("dirty-only")
+                .help("Upload files on every close, never (leaving it to the writeback queue), or when they changed"),
+        )
+        .arg(
+            Arg::new("cache-size")
+                .long("cache-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Evict the least recently opened files once cached contents exceed this size"),
+        )
+        .arg(
+            Arg::new("writeback-high-water")
+                .long("writeback-high-water")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Upload local changes before accepting more writes once this many bytes wait for upload"),
+        )
+        .arg(
+            Arg::new("writeback-low-water")
+                .long("writeback-low-water")
+                .value_name("BYTES")
+                .takes_value(true)
+                .requires("writeback-high-water")
+                .help("Bytes left waiting for upload once writes resume, default to half the high-water mark"),
+        )
+        .arg(
+            Arg::new("metadata-only")
+                .long("metadata-only")
+                .help("Serve attributes and listings only, opening a file fails with EACCES"),
+        )
+        .arg(
+            Arg::new("memory-cache")
+                .long("memory-cache")
+                .conflicts_with("dedup-cache")
+                .help("Keep cached file contents in memory instead of under the data directory"),
+        )
+        .arg(
+            Arg::new("dedup-cache")
+                .long("dedup-cache")
+                .help("Store identical objects (same md5) once in the cache, hard-linked between files"),
+        )
+        .arg(
+            Arg::new("readonly-after")
+                .long("readonly-after")
+                .value_name("RFC3339_TIME")
+                .takes_value(true)
+                .help("Turn the mount read-only at this time, e.g. 2024-05-01T18:00:00Z"),
+        )
+        .arg(
+            Arg::new("origin-bucket")
+                .long("origin-bucket")
+                .value_name("bucket_name")
+                .takes_value(true)
+                .help("Read objects missing from the bucket from this origin bucket"),
+        )
+        .arg(
+            Arg::new("origin-fill")
+                .long("origin-fill")
+                .requires("origin-bucket")
+                .help("Copy objects read from the origin bucket into the bucket"),
+        )
         .arg(
             Arg::new("direct-io")
                 .long("direct-io")
@@ -79,23 +450,367 @@ fn main() {
     if matches.is_present("allow-root") {
         options.push(MountOption::AllowRoot);
     }
+    add_mount_options(&mut options, matches.values_of("mount-option").unwrap_or_default());
+    // The kernel caps read requests by the max_read mount option, and buffered reads by the readahead
+    let max_read: Option<u32> = matches
+        .is_present("max-read")
+        .then(|| matches.value_of_t("max-read").unwrap_or_else(|e| e.exit()));
+    if let Some(max_read) = max_read {
+        options.push(MountOption::CUSTOM(format!("max_read={}", max_read)));
+    }
+    let fsync_on_close = match matches.value_of("fsync-on-close") {
+        Some("always") => FsyncOnClose::Always,
+        Some("never") => FsyncOnClose::Never,
+        _ => FsyncOnClose::DirtyOnly,
+    };
+    let mut writeback_delay = matches
+        .is_present("writeback-delay")
+        .then(|| Duration::from_secs(matches.value_of_t("writeback-delay").unwrap_or_else(|e| e.exit())));
+    // Closes never upload, so the writeback queue has to
+    if fsync_on_close == FsyncOnClose::Never && writeback_delay.is_none() {
+        writeback_delay = Some(NEVER_ON_CLOSE_WRITEBACK_DELAY);
+    }
+    let writeback_high_water: Option<u64> = matches
+        .is_present("writeback-high-water")
+        .then(|| matches.value_of_t("writeback-high-water").unwrap_or_else(|e| e.exit()));
+    let writeback_low_water: u64 = if matches.is_present("writeback-low-water") {
+        matches.value_of_t("writeback-low-water").unwrap_or_else(|e| e.exit())
+    } else {
+        writeback_high_water.unwrap_or(0) / 2
+    };
+    if matches!(writeback_high_water, Some(high_water) if writeback_low_water > high_water) {
+        error!("--writeback-low-water must not be above --writeback-high-water");
+        std::process::exit(1);
+    }
+    let cache_size: Option<u64> = matches
+        .is_present("cache-size")
+        .then(|| matches.value_of_t("cache-size").unwrap_or_else(|e| e.exit()));
+    // Settings in the config file take precedence over the command line
+    let limits = Arc::new(Limits::new(cache_size, writeback_high_water, writeback_low_water));
+    if let Some(config_path) = matches.value_of("config") {
+        if let Err(e) = config::reload(config_path, &limits) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        config::watch_sighup(config_path.to_string(), limits.clone());
+    }
     let data_dir: String = matches.value_of("data-dir").unwrap_or_default().to_string();
-    let result = fuser::mount2(
-        S3FS::new(
-            data_dir,
-            matches.is_present("direct-io"),
-            GcsWorker::new(bucket)
+    let immutable_globs = matches
+        .values_of("immutable-glob")
+        .unwrap_or_default()
+        .map(|glob| {
+            glob::Pattern::new(glob).unwrap_or_else(|e| {
+                error!("invalid immutable glob {}: {}", glob, e);
+                std::process::exit(1);
+            })
+        })
+        .collect();
+    let http = HttpOptions {
+        max_idle_per_host: matches
+            .value_of_t("http-max-idle-per-host")
+            .unwrap_or_else(|e| e.exit()),
+        idle_timeout: Duration::from_secs(
+            matches
+                .value_of_t("http-idle-timeout")
+                .unwrap_or_else(|e| e.exit()),
         ),
-        mountpoint,
-        &options,
+        tcp_keepalive: Duration::from_secs(
+            matches
+                .value_of_t("tcp-keepalive")
+                .unwrap_or_else(|e| e.exit()),
+        ),
+        connect_timeout: matches.is_present("connect-timeout").then(|| {
+            Duration::from_secs(
+                matches
+                    .value_of_t("connect-timeout")
+                    .unwrap_or_else(|e| e.exit()),
+            )
+        }),
+        http2_only: matches.is_present("http2"),
+        ca_cert: matches.value_of("endpoint-ca-cert").map(|path| {
+            load_ca_cert(path).unwrap_or_else(|e| {
+                error!("unable to load CA certificate {}: {}", path, e);
+                std::process::exit(1);
+            })
+        }),
+        insecure_skip_verify: matches.is_present("insecure-skip-verify"),
+    };
+    let backend = matches.value_of("backend").unwrap_or_default();
+    let gcs_endpoint = matches.value_of("gcs-endpoint");
+    let gcs_credential = matches.value_of("gcs-credential");
+    let gcs_scope = matches.value_of("gcs-scope");
+    // The key file is only read on the first request, so a bad path is reported here instead
+    if let Some(path) = gcs_credential {
+        if let Err(e) = std::fs::metadata(path) {
+            error!("unable to read GCS credential {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    let mut download = DownloadOptions::default();
+    if matches.is_present("download-block-size") {
+        download.block_size = matches.value_of_t("download-block-size").unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("download-threads") {
+        download.threads = matches.value_of_t("download-threads").unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("upload-part-size") {
+        download.upload_part_size = matches.value_of_t("upload-part-size").unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("max-upload-parts") {
+        download.max_upload_parts = matches.value_of_t("max-upload-parts").unwrap_or_else(|e| e.exit());
+    }
+    if download.block_size == 0 || download.threads == 0 || download.upload_part_size == 0 || download.max_upload_parts == 0 {
+        error!("--download-block-size, --download-threads, --upload-part-size and --max-upload-parts must be at least 1");
+        std::process::exit(1);
+    }
+    let s3_endpoint = matches.value_of("s3-endpoint");
+    let s3_region = matches.value_of("s3-region");
+    // The origin bucket lives on the same service and is reached with the same settings
+    let prefix_transform = matches.value_of("object-prefix-transform").map(|spec| {
+        PrefixTransform::parse(spec).unwrap_or_else(|e| {
+            error!("invalid --object-prefix-transform {}", e);
+            std::process::exit(1);
+        })
+    });
+    let worker = |bucket: String, http: HttpOptions| -> Box<dyn StorageBackend> {
+        let worker: Box<dyn StorageBackend> = match backend {
+            "s3" => Box::new(S3Worker::with_config(bucket, http, download, s3_endpoint, s3_region)),
+            _ => Box::new(GcsWorker::with_config(bucket, http, download, gcs_endpoint, gcs_credential, gcs_scope)),
+        };
+        // The origin bucket mirrors the primary one, so both are laid out the same way
+        match &prefix_transform {
+            Some(transform) => Box::new(TransformedBackend::new(worker, transform.clone())),
+            None => worker,
+        }
+    };
+    let cache: Box<dyn ContentCache> = if matches.is_present("memory-cache") {
+        Box::new(MemoryCache::new())
+    } else {
+        Box::new(FileCache::new(&data_dir))
+    };
+    let primary = worker(bucket.clone(), http.clone());
+    if matches.is_present("fail-fast-on-auth-error") {
+        // A single stat, refused credentials fail it the same way whether or not the key exists
+        let probe = tokio::runtime::Runtime::new().unwrap().block_on(primary.is_exist(AUTH_PROBE_KEY));
+        if let Some((code, message)) = auth_failure(&probe, &bucket) {
+            error!("{}", message);
+            std::process::exit(code);
+        }
+    }
+    let fs = S3FS::new(
+        data_dir,
+        FsOptions {
+            direct_io: matches.is_present("direct-io"),
+            show_meta_files: matches.is_present("show-meta-files"),
+            immutable_globs,
+            // Lazy by default, walking a large bucket up front makes mounting take minutes
+            discovery_depth: match matches.value_of("discovery-depth") {
+                None => Some(0),
+                Some("all") => None,
+                Some(_) => Some(matches.value_of_t("discovery-depth").unwrap_or_else(|e| e.exit())),
+            },
+            origin_fill: matches.is_present("origin-fill"),
+            max_write: matches
+                .is_present("max-write")
+                .then(|| matches.value_of_t("max-write").unwrap_or_else(|e| e.exit())),
+            max_readahead: max_read,
+            readdir_order: match matches.value_of("readdir-order") {
+                Some("mtime") => ReaddirOrder::Mtime,
+                Some("size") => ReaddirOrder::Size,
+                _ => ReaddirOrder::Name,
+            },
+            kernel_writeback: matches.is_present("kernel-writeback"),
+            serve_cached: matches.is_present("serve-cached"),
+            dedup_cache: matches.is_present("dedup-cache"),
+            readonly_after: matches.value_of("readonly-after").map(|deadline| {
+                OffsetDateTime::parse(deadline, &Rfc3339)
+                    .unwrap_or_else(|e| {
+                        error!("invalid --readonly-after time {}: {}", deadline, e);
+                        std::process::exit(1);
+                    })
+                    .into()
+            }),
+            oversize_policy: match matches.value_of("oversize-policy") {
+                Some("error") => OversizePolicy::Error,
+                _ => OversizePolicy::Stream,
+            },
+            stream_reads: matches.is_present("stream-reads"),
+            writeback_delay,
+            fsync_on_close,
+            keep_marker: matches.value_of("keep-marker").unwrap().to_string(),
+            max_symlink_depth: matches.value_of_t("max-symlink-depth").unwrap_or_else(|e| e.exit()),
+            shutdown_grace: matches
+                .is_present("shutdown-grace")
+                .then(|| Duration::from_secs(matches.value_of_t("shutdown-grace").unwrap_or_else(|e| e.exit()))),
+            hardlink_policy: match matches.value_of("hardlink-upload") {
+                Some("all") => HardlinkPolicy::All,
+                _ => HardlinkPolicy::Canonical,
+            },
+            metadata_only: matches.is_present("metadata-only"),
+            single_object: matches.value_of("single-object").map(str::to_string),
+            bsd_groups: matches.is_present("bsd-groups"),
+            limits,
+            append_segments: matches.is_present("append-segments"),
+            readahead_blocks: matches
+                .is_present("readahead-blocks")
+                .then(|| matches.value_of_t("readahead-blocks").unwrap_or_else(|e| e.exit())),
+            prefetch_head_bytes: if matches.is_present("prefetch-head-bytes") {
+                matches.value_of_t("prefetch-head-bytes").unwrap_or_else(|e| e.exit())
+            } else {
+                0
+            },
+            prefetch_tail_bytes: if matches.is_present("prefetch-tail-bytes") {
+                matches.value_of_t("prefetch-tail-bytes").unwrap_or_else(|e| e.exit())
+            } else {
+                0
+            },
+        },
+        primary,
+        matches
+            .value_of("origin-bucket")
+            .map(|origin| worker(origin.to_string(), http)),
+        cache,
     );
+    watch_sigusr2(fs.invalidation_trigger());
+    if let Some(delay) = writeback_delay {
+        watch_upload_queue(fs.upload_queue(), delay, mountpoint.clone());
+    }
+    let result = fuser::mount2(fs, &mountpoint, &options);
     if let Err(e) = result {
-        // Return a special error code for permission denied, which usually indicates that
-        // "user_allow_other" is missing from /etc/fuse.conf
-        if e.kind() == ErrorKind::PermissionDenied {
-            error!("{}", e.to_string());
-            std::process::exit(2);
+        let (code, message) = mount_failure(&e, &mountpoint);
+        error!("{}", message);
+        std::process::exit(code);
+    }
+
+}
+
+// Exit code and message for a failed mount. Permission denied usually means "user_allow_other"
+// is missing from /etc/fuse.conf, the other common failures get a hint and their own code.
+fn mount_failure(e: &std::io::Error, mountpoint: &str) -> (i32, String) {
+    if e.kind() == ErrorKind::PermissionDenied {
+        return (2, e.to_string());
+    }
+    match e.raw_os_error() {
+        Some(libc::ENOENT) => (
+            3,
+            format!("{}: mount point {} doesn't exist, create it with `mkdir -p {}`", e, mountpoint, mountpoint),
+        ),
+        Some(libc::ENOTDIR) => (4, format!("{}: mount point {} is not a directory", e, mountpoint)),
+        Some(libc::EBUSY) => (
+            5,
+            format!(
+                "{}: {} is busy or already mounted, unmount the stale mount with `fusermount -u {}`",
+                e, mountpoint, mountpoint
+            ),
+        ),
+        _ => (1, format!("Unable to mount {}: {}", mountpoint, e)),
+    }
+}
+
+// Exit code and message when the fail-fast probe found the credentials refused
+fn auth_failure(probe: &WorkerResult<bool>, bucket: &str) -> Option<(i32, String)> {
+    match probe {
+        Err(e @ WorkerError::PermissionDenied(_)) => {
+            Some((6, format!("authentication failed: check credentials for bucket {}: {}", bucket, e)))
         }
+        _ => None,
+    }
+}
+
+// Add the comma separated --mount-option values to the mount options, replacing the defaults
+// they conflict with rather than passing both
+fn add_mount_options<'a>(options: &mut Vec<MountOption>, values: impl Iterator<Item = &'a str>) {
+    for option in values.flat_map(|value| value.split(',')).filter_map(parse_mount_option) {
+        match option {
+            MountOption::RO => options.retain(|o| *o != MountOption::RW),
+            MountOption::FSName(_) => options.retain(|o| !matches!(o, MountOption::FSName(_))),
+            _ => {}
+        }
+        options.push(option);
+    }
+}
+
+// Map a standard mount option to its fuser counterpart, unknown ones are left out
+fn parse_mount_option(option: &str) -> Option<MountOption> {
+    let option = match option {
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        _ => match option.split_once('=') {
+            Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+            Some(("subtype", subtype)) => MountOption::Subtype(subtype.to_string()),
+            _ => {
+                warn!("unknown mount option {}, ignoring it", option);
+                return None;
+            }
+        },
+    };
+    Some(option)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount_options(values: &[&str]) -> Vec<MountOption> {
+        let mut options = vec![MountOption::RW, MountOption::FSName("s3-fuse".to_string())];
+        add_mount_options(&mut options, values.iter().copied());
+        options
+    }
+
+    #[test]
+    fn mount_options_reach_the_mount() {
+        let options = mount_options(&["nosuid"]);
+        assert!(options.contains(&MountOption::NoSuid));
+        assert!(options.contains(&MountOption::RW));
+        // Comma separated, replacing the defaults they conflict with
+        let options = mount_options(&["ro,fsname=bucket", "noexec"]);
+        assert_eq!(
+            options,
+            vec![MountOption::RO, MountOption::FSName("bucket".to_string()), MountOption::NoExec]
+        );
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn unknown_mount_options_are_skipped() {
+        assert_eq!(parse_mount_option("nosuchoption"), None);
+        assert_eq!(parse_mount_option("max_reed=4096"), None);
+        let options = mount_options(&["nosuchoption,nodev"]);
+        assert_eq!(
+            options,
+            vec![MountOption::RW, MountOption::FSName("s3-fuse".to_string()), MountOption::NoDev]
+        );
+    }
+
+    #[test]
+    fn mount_failures_exit_with_their_own_code() {
+        let code = |errno| mount_failure(&std::io::Error::from_raw_os_error(errno), "/mnt/bucket").0;
+        assert_eq!(code(libc::EPERM), 2);
+        assert_eq!(code(libc::EACCES), 2);
+        assert_eq!(code(libc::ENOENT), 3);
+        assert_eq!(code(libc::ENOTDIR), 4);
+        assert_eq!(code(libc::EBUSY), 5);
+        assert_eq!(code(libc::EINVAL), 1);
+        let (_, message) = mount_failure(&std::io::Error::from_raw_os_error(libc::ENOENT), "/mnt/bucket");
+        assert!(message.contains("mkdir -p /mnt/bucket"), "{}", message);
+
+        let refused = Err(WorkerError::PermissionDenied("403".to_string()));
+        assert_eq!(auth_failure(&refused, "bucket").map(|(code, _)| code), Some(6));
+        assert_eq!(auth_failure(&Err(WorkerError::Network("reset".to_string())), "bucket"), None);
+        assert_eq!(auth_failure(&Ok(false), "bucket"), None);
+    }
+}
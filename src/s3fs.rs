@@ -1,25 +1,35 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::ffi::OsStr;
 use fuser;
 use std::{io, fs};
 use std::path::{Path, PathBuf};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Seek, Write, SeekFrom};
+use std::io::{BufRead, BufReader};
 use fuser::{
     Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request, KernelConfig, FUSE_ROOT_ID, ReplyOpen, ReplyWrite, ReplyCreate, ReplyEmpty
+    Request, KernelConfig, FUSE_ROOT_ID, ReplyOpen, ReplyWrite, ReplyCreate, ReplyEmpty, ReplyXattr,
+    ReplyStatfs, TimeOrNow,
 };
-use fuser::consts::FOPEN_DIRECT_IO;
-use std::sync::atomic::{AtomicU64, Ordering};
+use fuser::consts::{FOPEN_DIRECT_IO, FUSE_WRITEBACK_CACHE};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 use std::os::raw::c_int;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::os::unix::ffi::OsStrExt;
-use log::debug;
-use std::cmp::min;
-use std::os::unix::fs::FileExt;
-use crate::s3util::GcsWorker;
+use std::os::unix::io::AsRawFd;
+use log::{debug, error, info, warn};
+use std::cmp::{min, Reverse};
+use std::os::unix::fs::MetadataExt;
+use crate::cache::{statvfs, ContentCache};
+use crate::config::Limits;
+use crate::s3util::{ObjectHeaders, StorageBackend, WorkerError, WorkerResult, SYMLINK_CONTENT_TYPE};
+use opendal::{Metadata, Reader};
+use futures::AsyncReadExt;
 use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
 use time::OffsetDateTime;
 use async_recursion::async_recursion;
 
@@ -31,13 +41,35 @@ const FMODE_EXEC: i32 = 0x20;
 // Note: This isn't safe, since the client can modify those bits.
 const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
 const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
+// Virtual "<name>.s3meta" files reuse their object's inode number with this bit set
+const META_INODE_BIT: u64 = 1 << 63;
+const META_FILE_SUFFIX: &[u8] = b".s3meta";
+// Largest chunk copy_file_range moves at once when the cache can't copy between files
+const COPY_BUFFER_SIZE: u64 = 1024 * 1024;
+// Computed extended attribute on directories holding the summed size of everything below them
+const TREE_SIZE_XATTR: &[u8] = b"user.s3fs.tree-size";
+// Extended attributes of files stored as headers of their object rather than kept locally only
+const CACHE_CONTROL_XATTR: &[u8] = b"user.s3.cache-control";
+const CONTENT_DISPOSITION_XATTR: &[u8] = b"user.s3.content-disposition";
+// How long a computed tree size is reused before walking the descriptors again
+const TREE_SIZE_TTL: Duration = Duration::from_secs(5);
+// Longest a write's inode update is held in memory before it is written to disk
+const INODE_WRITEBACK_INTERVAL: Duration = Duration::from_secs(1);
+// Files waiting for a --writeback-delay upload above which all of them are uploaded right away
+const WRITEBACK_QUEUE_LIMIT: usize = 64;
+// Looked up in the root by the writeback thread to get the queue drained, see watch_upload_queue
+const WRITEBACK_WAKEUP_NAME: &[u8] = b".s3fs-writeback-wakeup";
 type Inode = u64;
 type DirectoryDescriptor = BTreeMap<Vec<u8>, (Inode, FileKind)>;
+type DirectoryListing = Box<dyn Iterator<Item = (Vec<u8>, Inode, FileKind)>>;
+// A name of a file, as the directory holding it and the name in that directory
+type Link = (Inode, Vec<u8>);
 
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq)]
 enum FileKind {
     File,
     Directory,
+    Symlink,
 }
 
 impl From<FileKind> for fuser::FileType {
@@ -45,6 +77,7 @@ impl From<FileKind> for fuser::FileType {
         match kind {
             FileKind::File => fuser::FileType::RegularFile,
             FileKind::Directory => fuser::FileType::Directory,
+            FileKind::Symlink => fuser::FileType::Symlink,
         }
     }
 }
@@ -61,6 +94,13 @@ fn time_now() -> (i64, u32) {
     time_from_system_time(&SystemTime::now())
 }
 
+fn time_from_time_or_now(time: TimeOrNow) -> (i64, u32) {
+    match time {
+        TimeOrNow::SpecificTime(time) => time_from_system_time(&time),
+        TimeOrNow::Now => time_now(),
+    }
+}
+
 fn time_from_system_time(system_time: &SystemTime) -> (i64, u32) {
     // Convert to signed 64-bit time with epoch at 0
     match system_time.duration_since(UNIX_EPOCH) {
@@ -72,7 +112,47 @@ fn time_from_system_time(system_time: &SystemTime) -> (i64, u32) {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+// Lazily decodes a serialized DirectoryDescriptor straight from its file. bincode stores a
+// map as its entry count followed by the (name, (inode, kind)) pairs in key order, so entries
+// can be read one at a time without building the whole BTreeMap
+struct DirectoryEntries {
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+impl Iterator for DirectoryEntries {
+    type Item = (Vec<u8>, (Inode, FileKind));
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        match bincode::deserialize_from(&mut self.reader) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                // A damaged descriptor lists what could be read before the damage
+                warn!("Unreadable directory entry: {}", e);
+                self.remaining = 0;
+                None
+            }
+        }
+    }
+}
+
+// Inode files start with this tag and a format version, so files written by a release with a
+// different InodeAttributes layout are detected instead of being misread
+const INODE_MAGIC: [u8; 4] = *b"S3FI";
+// Bump whenever InodeAttributes changes shape, keeping the previous shape for decode_inode
+const INODE_FORMAT_VERSION: u32 = 5;
+// With --append-segments the file "<key>" is stored as the objects "<key>.segments/00000000",
+// "<key>.segments/00000001", ... in the order they were appended
+const SEGMENTS_SUFFIX: &str = ".segments/";
+// Stands in for the md5 of a file read block by block while some of its blocks aren't cached,
+// so a cache left behind with holes is never taken for the whole object
+const PARTIAL_MARKER: &str = "partial";
+
+#[derive(Serialize, Deserialize, Clone)]
 struct InodeAttributes {
     pub inode: Inode,
     pub open_file_handles: u64, // Ref count of open file handles to this inode
@@ -80,13 +160,25 @@ struct InodeAttributes {
     pub last_accessed: (i64, u32),
     pub last_modified: (i64, u32),
     pub last_metadata_changed: (i64, u32),
+    // Birth time. Discovered objects take their last-modified time, as the bucket doesn't
+    // report when an object was first created.
+    pub created: (i64, u32),
     pub kind: FileKind,
     // Permissions and special mode bits
     pub mode: u16,
     pub hardlinks: u32,
     pub uid: u32,
     pub gid: u32,
-    pub md5: String
+    pub md5: String,
+    // Whether a directory's children have been listed from the backend yet
+    pub expanded: bool,
+    // Extended attributes set through the mount, by full name ("user.comment")
+    pub xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    // Lengths of the append segments uploaded so far, in order. Only used with --append-segments.
+    pub segments: Vec<u64>,
+    // The cached contents have changes that weren't uploaded yet. An empty md5 alone doesn't
+    // tell, files that were never downloaded or got evicted have none either.
+    pub dirty: bool,
 }
 
 impl From<InodeAttributes> for fuser::FileAttr {
@@ -101,7 +193,7 @@ impl From<InodeAttributes> for fuser::FileAttr {
                 attrs.last_metadata_changed.0,
                 attrs.last_metadata_changed.1,
             ),
-            crtime: SystemTime::UNIX_EPOCH,
+            crtime: system_time_from_time(attrs.created.0, attrs.created.1),
             kind: attrs.kind.into(),
             perm: attrs.mode,
             nlink: attrs.hardlinks,
@@ -114,26 +206,649 @@ impl From<InodeAttributes> for fuser::FileAttr {
     }
 }
 
+// Layouts of inode files written by earlier releases. decode_inode upgrades them field by
+// field, so a cache survives an upgrade with its local changes. Fields that didn't exist yet
+// get what a fresh discovery would give them.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct InodeAttributesV0 {
+    inode: Inode,
+    open_file_handles: u64,
+    size: u64,
+    last_accessed: (i64, u32),
+    last_modified: (i64, u32),
+    last_metadata_changed: (i64, u32),
+    kind: FileKind,
+    mode: u16,
+    hardlinks: u32,
+    uid: u32,
+    gid: u32,
+    md5: String,
+}
+
+// Also written without the format header, by the releases before inode files had one
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct InodeAttributesV1 {
+    inode: Inode,
+    open_file_handles: u64,
+    size: u64,
+    last_accessed: (i64, u32),
+    last_modified: (i64, u32),
+    last_metadata_changed: (i64, u32),
+    kind: FileKind,
+    mode: u16,
+    hardlinks: u32,
+    uid: u32,
+    gid: u32,
+    md5: String,
+    expanded: bool,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct InodeAttributesV2 {
+    inode: Inode,
+    open_file_handles: u64,
+    size: u64,
+    last_accessed: (i64, u32),
+    last_modified: (i64, u32),
+    last_metadata_changed: (i64, u32),
+    kind: FileKind,
+    mode: u16,
+    hardlinks: u32,
+    uid: u32,
+    gid: u32,
+    md5: String,
+    expanded: bool,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct InodeAttributesV3 {
+    inode: Inode,
+    open_file_handles: u64,
+    size: u64,
+    last_accessed: (i64, u32),
+    last_modified: (i64, u32),
+    last_metadata_changed: (i64, u32),
+    created: (i64, u32),
+    kind: FileKind,
+    mode: u16,
+    hardlinks: u32,
+    uid: u32,
+    gid: u32,
+    md5: String,
+    expanded: bool,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct InodeAttributesV4 {
+    inode: Inode,
+    open_file_handles: u64,
+    size: u64,
+    last_accessed: (i64, u32),
+    last_modified: (i64, u32),
+    last_metadata_changed: (i64, u32),
+    created: (i64, u32),
+    kind: FileKind,
+    mode: u16,
+    hardlinks: u32,
+    uid: u32,
+    gid: u32,
+    md5: String,
+    expanded: bool,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    segments: Vec<u64>,
+}
+
+// Every directory was listed at mount before discovery could be limited in depth
+impl From<InodeAttributesV0> for InodeAttributesV1 {
+    fn from(v0: InodeAttributesV0) -> Self {
+        InodeAttributesV1 {
+            inode: v0.inode,
+            open_file_handles: v0.open_file_handles,
+            size: v0.size,
+            last_accessed: v0.last_accessed,
+            last_modified: v0.last_modified,
+            last_metadata_changed: v0.last_metadata_changed,
+            kind: v0.kind,
+            mode: v0.mode,
+            hardlinks: v0.hardlinks,
+            uid: v0.uid,
+            gid: v0.gid,
+            md5: v0.md5,
+            expanded: true,
+        }
+    }
+}
+
+impl From<InodeAttributesV1> for InodeAttributesV2 {
+    fn from(v1: InodeAttributesV1) -> Self {
+        InodeAttributesV2 {
+            inode: v1.inode,
+            open_file_handles: v1.open_file_handles,
+            size: v1.size,
+            last_accessed: v1.last_accessed,
+            last_modified: v1.last_modified,
+            last_metadata_changed: v1.last_metadata_changed,
+            kind: v1.kind,
+            mode: v1.mode,
+            hardlinks: v1.hardlinks,
+            uid: v1.uid,
+            gid: v1.gid,
+            md5: v1.md5,
+            expanded: v1.expanded,
+            xattrs: BTreeMap::new(),
+        }
+    }
+}
+
+// Like a discovered object, the birth time is taken to be the last modification
+impl From<InodeAttributesV2> for InodeAttributesV3 {
+    fn from(v2: InodeAttributesV2) -> Self {
+        InodeAttributesV3 {
+            inode: v2.inode,
+            open_file_handles: v2.open_file_handles,
+            size: v2.size,
+            last_accessed: v2.last_accessed,
+            last_modified: v2.last_modified,
+            last_metadata_changed: v2.last_metadata_changed,
+            created: v2.last_modified,
+            kind: v2.kind,
+            mode: v2.mode,
+            hardlinks: v2.hardlinks,
+            uid: v2.uid,
+            gid: v2.gid,
+            md5: v2.md5,
+            expanded: v2.expanded,
+            xattrs: v2.xattrs,
+        }
+    }
+}
+
+impl From<InodeAttributesV3> for InodeAttributesV4 {
+    fn from(v3: InodeAttributesV3) -> Self {
+        InodeAttributesV4 {
+            inode: v3.inode,
+            open_file_handles: v3.open_file_handles,
+            size: v3.size,
+            last_accessed: v3.last_accessed,
+            last_modified: v3.last_modified,
+            last_metadata_changed: v3.last_metadata_changed,
+            created: v3.created,
+            kind: v3.kind,
+            mode: v3.mode,
+            hardlinks: v3.hardlinks,
+            uid: v3.uid,
+            gid: v3.gid,
+            md5: v3.md5,
+            expanded: v3.expanded,
+            xattrs: v3.xattrs,
+            segments: Vec::new(),
+        }
+    }
+}
+
+// An empty md5 marked local changes, as well as contents that were never downloaded. get_inode
+// clears the flag again for files without cached contents.
+impl From<InodeAttributesV4> for InodeAttributes {
+    fn from(v4: InodeAttributesV4) -> Self {
+        InodeAttributes {
+            inode: v4.inode,
+            open_file_handles: v4.open_file_handles,
+            size: v4.size,
+            last_accessed: v4.last_accessed,
+            last_modified: v4.last_modified,
+            last_metadata_changed: v4.last_metadata_changed,
+            created: v4.created,
+            kind: v4.kind,
+            mode: v4.mode,
+            hardlinks: v4.hardlinks,
+            uid: v4.uid,
+            gid: v4.gid,
+            dirty: v4.kind == FileKind::File && v4.md5.is_empty(),
+            md5: v4.md5,
+            expanded: v4.expanded,
+            xattrs: v4.xattrs,
+            segments: v4.segments,
+        }
+    }
+}
+
+// Order in which readdir lists a directory's entries, the dot entries always come first
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum ReaddirOrder {
+    // Byte order of the names, as stored in the directory descriptor
+    Name,
+    // Most recently modified first
+    Mtime,
+    // Largest first
+    Size,
+}
+
+// What open does with an object larger than the space left in the cache
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum OversizePolicy {
+    // Read handles fetch each requested range from the bucket, nothing is cached
+    Stream,
+    // Fail the open with EFBIG
+    Error,
+}
+
+// Where the contents of a file with several hard links are uploaded to
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum HardlinkPolicy {
+    // The file's first key, the keys of its other names are replaced by backend copies of it
+    Canonical,
+    // Every key, each one gets the contents uploaded
+    All,
+}
+
+// When closing a handle that may have written uploads the file
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum FsyncOnClose {
+    // Every close, even when nothing changed
+    Always,
+    // Never, the writeback queue uploads changed files in the background
+    Never,
+    // Closes of files with local changes
+    DirtyOnly,
+}
+
+// What a close does with the file's contents
+#[derive(Debug, PartialEq)]
+enum CloseUpload {
+    Skip,
+    Now,
+    Queue,
+}
+
+impl FsyncOnClose {
+    // `delayed` is whether --writeback-delay holds uploads back
+    fn on_close(self, dirty: bool, delayed: bool) -> CloseUpload {
+        match self {
+            FsyncOnClose::Always => CloseUpload::Now,
+            FsyncOnClose::Never | FsyncOnClose::DirtyOnly if dirty && delayed => CloseUpload::Queue,
+            FsyncOnClose::DirtyOnly if dirty => CloseUpload::Now,
+            _ => CloseUpload::Skip,
+        }
+    }
+}
+
+// Behaviour switches chosen at mount time
+pub(crate) struct FsOptions {
+    // Open files with FOPEN_DIRECT_IO, bypassing the kernel page cache
+    pub direct_io: bool,
+    // Present each file's backend metadata as a read-only "<name>.s3meta" JSON sibling
+    pub show_meta_files: bool,
+    // Keys matching any of these are never revalidated against the backend once cached
+    pub immutable_globs: Vec<glob::Pattern>,
+    // How many directory levels init lists eagerly, None walks the whole bucket
+    pub discovery_depth: Option<u32>,
+    // Copy objects read from the origin bucket into the primary one
+    pub origin_fill: bool,
+    // Largest write the kernel sends in one request, None keeps the kernel's default
+    pub max_write: Option<u32>,
+    // Largest readahead the kernel issues in one read request
+    pub max_readahead: Option<u32>,
+    pub readdir_order: ReaddirOrder,
+    // Let the kernel buffer and coalesce writes in its page cache before sending them
+    pub kernel_writeback: bool,
+    // Serve cached files to read handles without checking the backend for a newer version
+    pub serve_cached: bool,
+    // Share one cache file between objects with the same md5
+    pub dedup_cache: bool,
+    // Refuse mutations with EROFS from this time on
+    pub readonly_after: Option<SystemTime>,
+    pub oversize_policy: OversizePolicy,
+    // Refuse to open files, so the tree can be walked without pulling any contents
+    pub metadata_only: bool,
+    // Present only this key, as the single file in the root directory
+    pub single_object: Option<String>,
+    // New files and directories take their parent's group even without SGID
+    pub bsd_groups: bool,
+    // Bytes of cached file contents kept before the least recently opened files are evicted, and
+    // the writeback high and low water marks. The config file can change them on a live mount.
+    pub limits: Arc<Limits>,
+    // Never overwrite an object, each upload adds a segment holding the bytes appended since
+    pub append_segments: bool,
+    // Fetch files opened for reading block by block as they are read, prefetching this many
+    // blocks ahead of sequential reads
+    pub readahead_blocks: Option<u32>,
+    // Bytes at the start and the end of a file read block by block that open fetches right away
+    pub prefetch_head_bytes: u64,
+    pub prefetch_tail_bytes: u64,
+    pub hardlink_policy: HardlinkPolicy,
+    // Read files opened for reading through an object reader kept open per handle, without
+    // caching them
+    pub stream_reads: bool,
+    // How long release leaves a file's upload queued, so repeated writes make one upload
+    pub writeback_delay: Option<Duration>,
+    pub fsync_on_close: FsyncOnClose,
+    // How long unmounting may spend uploading files with local changes
+    pub shutdown_grace: Option<Duration>,
+    // On backends that refuse zero-byte "dir/" markers, the empty object inside every directory
+    // that keeps it listed
+    pub keep_marker: String,
+    // Symbolic links readlink follows through the mount before giving up with ELOOP
+    pub max_symlink_depth: u32,
+}
+
+// A file opened with --readahead-blocks, its contents are fetched into the cache block by block
+// as they are read instead of downloaded whole by open
+struct PartialFile {
+    key: String,
+    // The object was read from the origin bucket
+    from_origin: bool,
+    size: u64,
+    // Version of the object, taken once every block is cached
+    md5: String,
+    // Blocks already in the cache
+    fetched: HashSet<u64>,
+    // Blocks downloaded in the background, None while in flight. Failed ones are removed.
+    prefetched: Arc<Mutex<HashMap<u64, Option<Vec<u8>>>>>,
+    // Where each file handle's last read ended, to recognize sequential reads
+    read_ends: HashMap<u64, u64>,
+}
+
 // Stores inode metadata data in "$data_dir/inodes" and file contents in "$data_dir/contents"
 // Directory data is stored in the file's contents, as a serialized DirectoryDescriptor
+//
+// Requests are handled one at a time on fuser's session thread through &mut self, so no two
+// operations on an inode overlap and there is nothing to lock per inode. The other threads
+// never touch the inode, directory or cache files: the SIGUSR2 and SIGHUP watchers only set
+// the invalidation flag, the log level and the limits, the writeback thread only reads the
+// upload queue and sends a lookup through the mount, and prefetch threads leave the blocks
+// they read in PartialFile::prefetched for the session thread to cache.
 pub(crate) struct S3FS {
     data_dir: String,
     next_file_handle: AtomicU64,
-    direct_io: bool,
-    worker: GcsWorker,
+    options: FsOptions,
+    worker: Box<dyn StorageBackend>,
+    // Slower bucket backing the primary one, objects missing from the primary are read from here
+    origin: Option<Box<dyn StorageBackend>>,
+    // Set from the signal thread, the cache is dropped by the next request that checks it
+    invalidate_requested: Arc<AtomicBool>,
+    // Contents of cached files
+    cache: Box<dyn ContentCache>,
+    // Recently computed tree sizes by directory inode, with when they were computed
+    tree_sizes: HashMap<Inode, (Instant, u64)>,
+    // Files too large for the cache, read from the bucket range by range
+    streamed: HashSet<Inode>,
+    // Open files whose blocks are fetched as they are read
+    partial: HashMap<Inode, PartialFile>,
+    // Object readers of handles streaming a file, with the offset each one is at. They live on
+    // their own runtime, as their connections must outlive a single request.
+    readers: HashMap<u64, (u64, Reader)>,
+    stream_runtime: Option<Runtime>,
+    // Inode updates from writes not on disk yet, with when the oldest of them was made
+    deferred_inodes: Mutex<HashMap<Inode, (Instant, InodeAttributes)>>,
+    // Size of every file written through the mount since its last upload
+    pending_uploads: Mutex<HashMap<Inode, u64>>,
+    // When each file that may have cached contents was last opened
+    cache_lru: Mutex<HashMap<Inode, Instant>>,
+    // Files whose upload was deferred by --writeback-delay, with when they were queued. Shared
+    // with the thread that gets it drained on an idle mount.
+    upload_queue: Arc<Mutex<HashMap<Inode, Instant>>>,
+    // The names this mount has seen each file under, as the directory and the name in it, in
+    // the order they were met. Keys are built from these instead of searching the tree.
+    names: Mutex<HashMap<Inode, Vec<Link>>>,
+    // The backend refused a directory marker, directories are kept with a --keep-marker object
+    // instead, and objects of that name are hidden. Found out by the first mkdir.
+    keep_markers: bool,
 }
 
 impl S3FS  {
     pub fn new(
         data_dir: String,
-        direct_io: bool,
-        worker: GcsWorker,
+        options: FsOptions,
+        worker: Box<dyn StorageBackend>,
+        origin: Option<Box<dyn StorageBackend>>,
+        cache: Box<dyn ContentCache>,
     ) -> S3FS {
+        let stream_runtime = options.stream_reads.then(|| Runtime::new().unwrap());
         S3FS {
             data_dir,
             next_file_handle: AtomicU64::new(1),
-            direct_io,
+            options,
             worker,
+            origin,
+            invalidate_requested: Arc::new(AtomicBool::new(false)),
+            cache,
+            tree_sizes: HashMap::new(),
+            streamed: HashSet::new(),
+            partial: HashMap::new(),
+            readers: HashMap::new(),
+            stream_runtime,
+            deferred_inodes: Mutex::new(HashMap::new()),
+            pending_uploads: Mutex::new(HashMap::new()),
+            cache_lru: Mutex::new(HashMap::new()),
+            upload_queue: Arc::new(Mutex::new(HashMap::new())),
+            names: Mutex::new(HashMap::new()),
+            keep_markers: false,
+        }
+    }
+
+    pub fn invalidation_trigger(&self) -> Arc<AtomicBool> {
+        self.invalidate_requested.clone()
+    }
+
+    pub fn upload_queue(&self) -> Arc<Mutex<HashMap<u64, Instant>>> {
+        self.upload_queue.clone()
+    }
+
+    fn invalidate_if_requested(&self) {
+        if self.invalidate_requested.swap(false, Ordering::SeqCst) {
+            self.invalidate_cache();
+        }
+    }
+
+    // Files cached by an earlier mount count as opened before any file of this one
+    fn load_cache_lru(&self) {
+        let mut lru = self.cache_lru.lock().unwrap();
+        for entry in fs::read_dir(Path::new(&self.data_dir).join("inodes")).unwrap() {
+            let inode = match entry.unwrap().file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            if matches!(self.get_inode(inode), Ok(attrs) if attrs.kind == FileKind::File)
+                && self.cache.cached_len(inode).is_some()
+            {
+                lru.insert(inode, Instant::now());
+            }
+        }
+    }
+
+    // Bring the cached contents back under --cache-size by evicting the least recently opened
+    // files that are closed and match their object. Their inodes stay, the next open downloads
+    // them again. `keep` is the file just downloaded, which is about to be opened.
+    fn enforce_cache_size(&self, keep: Inode) {
+        let limit = match self.options.limits.cache_size() {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut lru: Vec<_> = self.cache_lru.lock().unwrap().iter().map(|(inode, opened)| (*inode, *opened)).collect();
+        let mut total: u64 = lru.iter().filter_map(|(inode, _)| self.cache.cached_len(*inode)).sum();
+        if total <= limit {
+            return;
+        }
+        lru.sort_by_key(|(_, opened)| *opened);
+        let mut evicted = 0;
+        for (inode, _) in lru {
+            if total <= limit {
+                break;
+            }
+            let size = match self.cache.cached_len(inode) {
+                Some(size) => size,
+                None => {
+                    self.cache_lru.lock().unwrap().remove(&inode);
+                    continue;
+                }
+            };
+            let mut attrs = match self.get_inode(inode) {
+                Ok(attrs) => attrs,
+                Err(_) => continue,
+            };
+            if inode == keep || attrs.dirty || attrs.open_file_handles > 0 {
+                continue;
+            }
+            if let Err(e) = self.cache.evict(inode) {
+                warn!("Unable to evict inode {}: {}", inode, e);
+                continue;
+            }
+            self.release_dedup_object(&attrs.md5);
+            attrs.md5 = "".to_string();
+            // A failed write is logged, the next open downloads the file either way
+            let _ = self.write_inode(&attrs);
+            self.cache_lru.lock().unwrap().remove(&inode);
+            total = total.saturating_sub(size);
+            evicted += 1;
+        }
+        debug!("Evicted {} cached files, {} bytes cached", evicted, total);
+    }
+
+    // Drop the cached contents of every file that still matches its backend object, so the next
+    // open downloads it again. Dirty files are kept, as their changes haven't been uploaded, and
+    // so are open ones.
+    fn invalidate_cache(&self) {
+        let (mut dropped, mut kept) = (0, 0);
+        for entry in fs::read_dir(Path::new(&self.data_dir).join("inodes")).unwrap() {
+            let inode = match entry.unwrap().file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            let mut attrs = match self.get_inode(inode) {
+                Ok(attrs) if attrs.kind == FileKind::File => attrs,
+                _ => continue,
+            };
+            // Files being read block by block are open, and their blocks aren't all there yet
+            if self.cache.cached_len(inode).is_none() || self.partial.contains_key(&inode) {
+                continue;
+            }
+            // Open handles keep reading and writing the cached file, it is left for a later
+            // invalidation
+            if attrs.dirty || attrs.open_file_handles > 0 {
+                kept += 1;
+                continue;
+            }
+            // open() downloads files without contents again, their times stay those of the object
+            if let Err(e) = self.cache.evict(inode) {
+                warn!("Unable to evict inode {}: {}", inode, e);
+                continue;
+            }
+            self.release_dedup_object(&attrs.md5);
+            attrs.md5 = "".to_string();
+            // A failed write is logged, the next open downloads the file either way
+            let _ = self.write_inode(&attrs);
+            dropped += 1;
+        }
+        info!("Invalidated {} cached files, kept {} that are open or have local changes", dropped, kept);
+    }
+
+    // Writes hold their inode updates back for up to INODE_WRITEBACK_INTERVAL, so after a crash a
+    // dirty file's inode can still have the size from before its last writes. Their data went
+    // to the cache file first, which is then longer, so its length is taken instead.
+    fn recover_dirty_sizes(&self) {
+        for entry in fs::read_dir(Path::new(&self.data_dir).join("inodes")).unwrap() {
+            let inode = match entry.unwrap().file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            let mut attrs = match self.get_inode(inode) {
+                Ok(attrs) if attrs.dirty => attrs,
+                _ => continue,
+            };
+            match self.cache.cached_len(inode) {
+                Some(len) if len > attrs.size => {
+                    info!("Inode {} grew to {} bytes before an unclean shutdown, was {}", inode, len, attrs.size);
+                    attrs.size = len;
+                    // A failed write is logged, the size is recovered again on the next mount
+                    let _ = self.write_inode(&attrs);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Upload every cached file whose contents changed since its last download or upload,
+    // e.g. files still open when the mount goes away or whose upload on release failed.
+    // Files left once `deadline` has passed are logged and stay dirty, the next mount uploads
+    // them. An upload already running when it passes is finished.
+    fn upload_dirty_files(&self, deadline: Option<Instant>) {
+        let (mut uploaded, mut failed, mut skipped) = (0, 0, 0);
+        for entry in fs::read_dir(Path::new(&self.data_dir).join("inodes")).unwrap() {
+            let inode = match entry.unwrap().file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            let mut attrs = match self.get_inode(inode) {
+                Ok(attrs) if attrs.dirty && attrs.hardlinks > 0 => attrs,
+                _ => continue,
+            };
+            if self.cache.cached_len(inode).is_none() {
+                continue;
+            }
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                let name = self.get_filename_from_inode(inode).unwrap_or_else(|_| format!("inode {}", inode));
+                warn!("Out of shutdown grace, leaving {} for the next mount to upload", name);
+                skipped += 1;
+                continue;
+            }
+            match self.upload_content(&mut attrs) {
+                Ok(()) => uploaded += 1,
+                Err(_) => failed += 1,
+            }
+        }
+        if uploaded > 0 || failed > 0 || skipped > 0 {
+            info!(
+                "Uploaded {} files with local changes, {} failed, {} left for the next mount",
+                uploaded, failed, skipped
+            );
+        }
+    }
+
+    // Leave a release's upload to upload_queued_files, a file already queued keeps its place
+    fn queue_upload(&self, inode: Inode) {
+        self.upload_queue.lock().unwrap().entry(inode).or_insert_with(Instant::now);
+    }
+
+    // Upload the queued files whose delay has passed, or all of them once more than
+    // WRITEBACK_QUEUE_LIMIT are waiting. Requests check the queue as they come in, and
+    // watch_upload_queue makes one when files come due on an idle mount. Anything still queued
+    // at unmount is uploaded by upload_dirty_files.
+    fn upload_queued_files(&self) {
+        let delay = match self.options.writeback_delay {
+            Some(delay) => delay,
+            None => return,
+        };
+        let due: Vec<Inode> = {
+            let mut queue = self.upload_queue.lock().unwrap();
+            let all = queue.len() > WRITEBACK_QUEUE_LIMIT;
+            let due: Vec<Inode> = queue
+                .iter()
+                .filter(|(_, queued)| all || queued.elapsed() >= delay)
+                .map(|(inode, _)| *inode)
+                .collect();
+            for inode in &due {
+                queue.remove(inode);
+            }
+            due
+        };
+        for inode in due {
+            // Files uploaded in the meantime, e.g. to stay below --writeback-high-water, or
+            // unlinked are dropped from the queue
+            let mut attrs = match self.get_inode(inode) {
+                Ok(attrs) if attrs.dirty && attrs.hardlinks > 0 => attrs,
+                _ => continue,
+            };
+            // upload_content logs the failure, the file is tried again after another delay
+            if self.upload_content(&mut attrs).is_err() {
+                self.queue_upload(inode);
+            }
         }
     }
 
@@ -147,11 +862,27 @@ impl S3FS  {
         Ok(false)
     }
 
+    // Every mutating operation goes through here, so the mount turns read-only at the
+    // --readonly-after deadline without a remount
+    fn check_writable(&self) -> Result<(), c_int> {
+        writable_at(self.options.readonly_after, SystemTime::now())
+    }
+
+    // Creating, removing or renaming entries. A single object mount has exactly one file.
+    fn check_namespace_writable(&self) -> Result<(), c_int> {
+        self.check_writable()?;
+        if self.options.single_object.is_some() {
+            return Err(libc::EPERM);
+        }
+        Ok(())
+    }
+
     fn allocate_next_file_handle(&self, read: bool, write: bool) -> u64 {
         let mut fh = self.next_file_handle.fetch_add(1, Ordering::SeqCst);
         // Assert that we haven't run out of file handles
         assert!(fh < FILE_HANDLE_WRITE_BIT && fh < FILE_HANDLE_READ_BIT);
-        if read {
+        // With writeback caching the kernel reads through write-only handles to fill partial pages
+        if read || (write && self.options.kernel_writeback) {
             fh |= FILE_HANDLE_READ_BIT;
         }
         if write {
@@ -161,127 +892,209 @@ impl S3FS  {
         fh
     }
 
-    fn allocate_next_inode(&self) -> Inode {
+    fn allocate_next_inode(&self) -> Result<Inode, c_int> {
+        self.allocate_inodes(1)
+    }
+
+    // Highest inode number handed out so far
+    fn last_inode(&self) -> Result<Inode, c_int> {
         let path = Path::new(&self.data_dir).join("superblock");
-        let current_inode = if let Ok(file) = File::open(&path) {
-            bincode::deserialize_from(file).unwrap()
-        } else {
-            fuser::FUSE_ROOT_ID
-        };
+        match File::open(&path) {
+            Ok(file) => bincode::deserialize_from(file).map_err(|e| {
+                error!("Unreadable superblock: {}", e);
+                libc::EIO
+            }),
+            Err(_) => Ok(fuser::FUSE_ROOT_ID),
+        }
+    }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .unwrap();
-        bincode::serialize_into(file, &(current_inode + 1)).unwrap();
+    // Reserve `count` consecutive inode numbers with a single superblock update, returning the first
+    fn allocate_inodes(&self, count: u64) -> Result<Inode, c_int> {
+        let path = Path::new(&self.data_dir).join("superblock");
+        let current_inode = self.last_inode()?;
+
+        write_atomically(&path, &(current_inode + count)).map_err(|e| {
+            error!("Unable to write superblock: {}", e);
+            libc::EIO
+        })?;
 
-        current_inode + 1
+        Ok(current_inode + 1)
     }
 
     fn get_inode(&self, inode: Inode) -> Result<InodeAttributes, c_int> {
+        if let Some((_, attrs)) = self.deferred_inodes.lock().unwrap().get(&inode) {
+            return Ok(attrs.clone());
+        }
         let path = Path::new(&self.data_dir)
             .join("inodes")
             .join(inode.to_string());
-        if let Ok(file) = File::open(&path) {
-            Ok(bincode::deserialize_from(file).unwrap())
-        } else {
-            Err(libc::ENOENT)
+        let data = fs::read(&path).map_err(|_| libc::ENOENT)?;
+        let mut attrs = decode_inode(&data).map_err(|e| {
+            warn!("Unreadable inode {}: {}", inode, e);
+            libc::EIO
+        })?;
+        // Local changes only ever live in the cache
+        if attrs.dirty && self.cache.cached_len(inode).is_none() {
+            attrs.dirty = false;
+        }
+        Ok(attrs)
+    }
+
+    // Move all cached metadata and contents out of the way, so the tree gets rediscovered from
+    // the backend. Nothing is deleted, local changes that were never uploaded can still be
+    // recovered from the returned directory.
+    fn discard_cache(&self) -> io::Result<PathBuf> {
+        let data_dir = Path::new(&self.data_dir);
+        let aside = data_dir.join("discarded").join(time_now().0.to_string());
+        fs::create_dir_all(&aside)?;
+        for name in ["inodes", "contents", "objects", "superblock"] {
+            match fs::rename(data_dir.join(name), aside.join(name)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
         }
+        for dir in ["inodes", "contents", "objects"] {
+            fs::create_dir_all(data_dir.join(dir))?;
+        }
+        Ok(aside)
     }
 
-    fn write_inode(&self, inode: &InodeAttributes) {
+    fn write_inode(&self, inode: &InodeAttributes) -> Result<(), c_int> {
+        self.deferred_inodes.lock().unwrap().remove(&inode.inode);
         let path = Path::new(&self.data_dir)
             .join("inodes")
             .join(inode.inode.to_string());
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .unwrap();
-        bincode::serialize_into(file, inode).unwrap();
+        write_atomically(&path, &(INODE_MAGIC, INODE_FORMAT_VERSION, inode)).map_err(|e| {
+            error!("Unable to write inode {}: {}", inode.inode, e);
+            libc::EIO
+        })
     }
 
-    fn get_directory_content(&self, inode: Inode) -> Result<DirectoryDescriptor, c_int> {
-        let path = Path::new(&self.data_dir)
+    // Keep an inode update in memory, so a stream of small writes doesn't rewrite the inode
+    // file every time. It reaches the disk with the next write_inode, at the latest once it
+    // is INODE_WRITEBACK_INTERVAL old, on flush or release, or at unmount.
+    fn defer_inode_write(&self, inode: InodeAttributes) -> Result<(), c_int> {
+        let mut deferred = self.deferred_inodes.lock().unwrap();
+        let since = match deferred.get(&inode.inode) {
+            Some((since, _)) => *since,
+            None => Instant::now(),
+        };
+        if since.elapsed() < INODE_WRITEBACK_INTERVAL {
+            deferred.insert(inode.inode, (since, inode));
+            return Ok(());
+        }
+        drop(deferred);
+        self.write_inode(&inode)
+    }
+
+    fn write_deferred_inodes(&self) {
+        let deferred: Vec<_> = self.deferred_inodes.lock().unwrap().drain().collect();
+        // Failures are logged by write_inode, the remaining inodes are still written
+        for (_, (_, attrs)) in deferred {
+            let _ = self.write_inode(&attrs);
+        }
+    }
+
+    // The content file of a directory inode. Files and symlinks keep other data there, which
+    // must never be decoded as a descriptor.
+    fn directory_path(&self, inode: Inode) -> Result<PathBuf, c_int> {
+        if self.get_inode(inode)?.kind != FileKind::Directory {
+            return Err(libc::ENOTDIR);
+        }
+        Ok(Path::new(&self.data_dir)
             .join("contents")
-            .join(inode.to_string());
+            .join(inode.to_string()))
+    }
+
+    fn get_directory_content(&self, inode: Inode) -> Result<DirectoryDescriptor, c_int> {
+        let path = self.directory_path(inode)?;
+        if let Ok(file) = File::open(&path) {
+            bincode::deserialize_from(BufReader::new(file)).map_err(|e| {
+                warn!("Unreadable directory {}: {}", inode, e);
+                libc::EIO
+            })
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn directory_entries(&self, inode: Inode) -> Result<DirectoryEntries, c_int> {
+        let path = self.directory_path(inode)?;
         if let Ok(file) = File::open(&path) {
-            Ok(bincode::deserialize_from(file).unwrap())
+            let mut reader = BufReader::new(file);
+            let remaining = bincode::deserialize_from(&mut reader).map_err(|e| {
+                warn!("Unreadable directory {}: {}", inode, e);
+                libc::EIO
+            })?;
+            Ok(DirectoryEntries { reader, remaining })
         } else {
             Err(libc::ENOENT)
         }
     }
 
-    fn write_directory_content(&self, inode: Inode, entries: DirectoryDescriptor) {
+    fn write_directory_content(&self, inode: Inode, entries: DirectoryDescriptor) -> Result<(), c_int> {
         let path = Path::new(&self.data_dir)
             .join("contents")
             .join(inode.to_string());
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .unwrap();
-        bincode::serialize_into(file, &entries).unwrap();
+        write_atomically(&path, &entries).map_err(|e| {
+            error!("Unable to write directory {}: {}", inode, e);
+            libc::EIO
+        })
     }
 
     fn  lookup_name(&self, parent: u64, name: &OsStr) -> Result<InodeAttributes, c_int> {
+        // The dot entries are known without listing the directory from the bucket
+        match name.as_bytes() {
+            b"." => return self.get_inode(parent),
+            // The root is its own parent, also for caches written before it had a ".." entry
+            b".." if parent == FUSE_ROOT_ID => return self.get_inode(FUSE_ROOT_ID),
+            b".." => {
+                let (grandparent, _) = *self.get_directory_content(parent)?.get(b"..".as_ref()).ok_or(libc::EIO)?;
+                return self.get_inode(grandparent);
+            }
+            _ => {}
+        }
+        self.ensure_expanded(parent)?;
         let entries = self.get_directory_content(parent)?;
         if let Some((inode, _)) = entries.get(name.as_bytes()) {
             // TODO: check metadata of the file, if not consistent, update, otherwise, return
+            self.add_name(*inode, parent, name.as_bytes());
             return self.get_inode(*inode);
-        } else {
-            // let exists = Runtime::new().unwrap().block_on(self.worker.is_exist(name.to_str().unwrap())).unwrap();
-            // if exists {
-            //     let inode = self.allocate_next_inode();
-            //     let metadata = Runtime::new().unwrap().block_on(self.worker.get_stats(name.to_str().unwrap())).unwrap();
-            //     let parent_attrs = self.get_inode(parent).unwrap();
-            //     let mut attrs = InodeAttributes {
-            //         inode,
-            //         open_file_handles: 1,
-            //         size: 0,
-            //         last_accessed: time_now(),
-            //         last_modified: time_from_offsetdatatime(metadata.last_modified()),
-            //         last_metadata_changed: time_now(),
-            //         kind: FileKind::File,
-            //         mode: 0o777,
-            //         hardlinks: 1,
-            //         uid: parent_attrs.uid,
-            //         gid: parent_attrs.gid,
-            //         // use dummy metadata here
-            //         // md5: metadata.content_md5().unwrap().to_string(),
-            //         md5: "".to_string(),
-            //     };
-            //     let path = self.content_path(inode);
-            //     File::create(&path).unwrap();
-            //     let data = Runtime::new().unwrap().block_on(self.worker.get_data(name.to_str().unwrap())).unwrap();
-            //     if let Ok(mut file) = OpenOptions::new().write(true).open(&path) {
-            //         file.seek(SeekFrom::Start(0 as u64)).unwrap();
-            //         file.write_all(&data).unwrap();
-
-            //         attrs.last_metadata_changed = time_now();
-            //         attrs.last_modified = time_now();
-            //         if data.len() as usize > attrs.size as usize {
-            //             attrs.size = (data.len() as usize) as u64;
-            //         }
-            //         clear_suid_sgid(&mut attrs);
-            //         self.write_inode(&attrs);
-
-            //         let mut entries = self.get_directory_content(parent).unwrap();
-            //         entries.insert(name.as_bytes().to_vec(), (inode, attrs.kind));
-            //         self.write_directory_content(parent, entries);
-            //     } 
-                
-
-            //     return self.get_inode(inode);
-            // } else {
-            //     return Err(libc::ENOENT);
-            // }  
+        }
+        if self.options.single_object.is_some() {
+            return Err(libc::ENOENT);
+        }
+        let attrs = self.discover_name(parent, name)?;
+        self.add_name(attrs.inode, parent, name.as_bytes());
+        Ok(attrs)
+    }
+
+    // Pick up an object added to the bucket after its directory was listed, with a single stat
+    // instead of listing the directory again. Only files are found this way, a new directory
+    // shows up once its parent is listed again after a remount.
+    fn discover_name(&self, parent: Inode, name: &OsStr) -> Result<InodeAttributes, c_int> {
+        if self.keep_markers && OsStr::new(&self.options.keep_marker) == name {
             return Err(libc::ENOENT);
         }
+        let key = format!("{}{}", self.directory_key(parent)?, name.to_str().ok_or(libc::ENOENT)?);
+        let rt = Runtime::new().unwrap();
+        let metadata = match rt.block_on(self.stat_object(&key)) {
+            Ok((metadata, _)) if metadata.is_file() => metadata,
+            Ok(_) | Err(WorkerError::NotFound(_)) => return Err(libc::ENOENT),
+            Err(e) => return Err(e.errno()),
+        };
+        let parent_attrs = self.get_inode(parent)?;
+        let inode = self.allocate_next_inode()?;
+        let attrs = rt
+            .block_on(self.init_object(&key, inode, &parent_attrs, &metadata))
+            .map_err(|e| {
+                error!("Unable to add {}: {}", key, e);
+                e.errno()
+            })?;
+        let mut entries = self.get_directory_content(parent)?;
+        entries.insert(name.as_bytes().to_vec(), (inode, attrs.kind));
+        self.write_directory_content(parent, entries)?;
+        Ok(attrs)
     }
 
     fn check_file_handle_read(&self, file_handle: u64) -> bool {
@@ -292,127 +1105,1037 @@ impl S3FS  {
         (file_handle & FILE_HANDLE_WRITE_BIT) != 0
     }
 
-    fn content_path(&self, inode: Inode) -> PathBuf {
+    // Cache file shared by every inode whose object has this md5, md5s are base64 so '/' is swapped out
+    fn dedup_path(&self, md5: &str) -> PathBuf {
         Path::new(&self.data_dir)
-            .join("contents")
-            .join(inode.to_string())
+            .join("objects")
+            .join(md5.replace('/', "_"))
     }
 
-    //  TODO: The function is only a toy at the moment that it does not support finding files in directories other than root. If want to find any file path with the inode, one might need to find a way to get its parent dir's inode for any path.
-    fn get_filename_from_inode(&self, inode: Inode) -> String {
-        let entries = self.get_directory_content(FUSE_ROOT_ID).unwrap();
-        let filename = get_key_by_value(&entries, &(inode, FileKind::File)).unwrap();
-        let s = String::from_utf8_lossy(filename);
-        s.to_string()
+    // Fill an inode's content file with an object. With the dedup store, objects with the same
+    // md5 share one hard-linked file and only the first of them is downloaded.
+    fn fetch_content(
+        &self,
+        rt: &Runtime,
+        source: &dyn StorageBackend,
+        key: &str,
+        inode: Inode,
+        md5: Option<&str>,
+    ) -> WorkerResult<u64> {
+        let path = match self.cache.file_path(inode) {
+            Some(path) => path,
+            None => return self.fetch_content_through_file(rt, source, key, inode),
+        };
+        // Replace rather than truncate the old file, it may be shared with other inodes
+        let _ = fs::remove_file(&path);
+        let object = match md5 {
+            Some(md5) if self.options.dedup_cache => Some(self.dedup_path(md5)),
+            _ => None,
+        };
+        if let Some(object) = &object {
+            if fs::hard_link(object, &path).is_ok() {
+                return Ok(fs::metadata(&path)?.len());
+            }
+        }
+        let size = rt.block_on(source.get_data(key, path.to_str().unwrap()))?;
+        if let Some(object) = &object {
+            // Failing here only means this copy isn't shared
+            let _ = fs::hard_link(&path, object);
+        }
+        Ok(size)
     }
 
-    fn creation_mode(&self, mode: u32) -> u16 {
-        (mode & !(libc::S_ISUID | libc::S_ISGID) as u32) as u16
+    // Caches that don't keep files get the object through a temporary download
+    fn fetch_content_through_file(
+        &self,
+        rt: &Runtime,
+        source: &dyn StorageBackend,
+        key: &str,
+        inode: Inode,
+    ) -> WorkerResult<u64> {
+        let download = Path::new(&self.data_dir)
+            .join("contents")
+            .join(format!("{}.download", inode));
+        let size = rt.block_on(source.get_data(key, download.to_str().unwrap()));
+        let data = size.and_then(|_| Ok(fs::read(&download)?));
+        let _ = fs::remove_file(&download);
+        let data = data?;
+        self.cache.truncate(inode, 0)?;
+        self.cache.write_range(inode, 0, &data)?;
+        Ok(data.len() as u64)
     }
 
-    #[async_recursion]
-    async fn init_directories(&self, path: &str, parent: Inode)  -> Result<(), Box<dyn std::error::Error>>{
-        let entries = self.worker.list_dir(path).await?;
-        let mut parent_attrs =self.get_inode(parent).unwrap();
-        for file in entries {
-            let full_path = format!("{}{}", path, file);
-            let is_file = self.worker.is_file(&full_path).await?;
-
-            if is_file {
-                // let metadata =  self.worker.get_stats(full_path.as_str()).await?;
-                let file_inode = self.allocate_next_inode();
-                let attrs = InodeAttributes {
-                    inode: file_inode,
-                    open_file_handles: 1,
-                    size: 0,
-                    last_accessed: time_now(),
-                    // last_modified: time_from_offsetdatatime(metadata.last_modified()),
-                    last_modified: time_now(),
-                    last_metadata_changed: time_now(),
-                    kind: FileKind::File,
-                    mode: 0x777,
-                    hardlinks: 1,
-                    uid: parent_attrs.uid,
-                    gid: parent_attrs.gid,
-                    md5: "".to_string()
-                };
-                self.write_inode(&attrs);
-                let mut entries = self.get_directory_content(parent).unwrap();
-                entries.insert(file.as_bytes().to_vec(), (file_inode, attrs.kind));
-                self.write_directory_content(parent, entries);
-                
-                parent_attrs.last_modified = time_now();
-                parent_attrs.last_metadata_changed = time_now();
-                self.write_inode(&parent_attrs);
-            } else {
-                let dir_path = format!("{}/", full_path);
-                let dir_inode = self.allocate_next_inode();
-                let attrs = InodeAttributes {
-                    inode: dir_inode,
-                    open_file_handles: 1,
-                    size: 0,
-                    last_accessed: time_now(),
-                    last_modified: time_now(),
-                    last_metadata_changed: time_now(),
-                    kind: FileKind::Directory,
-                    mode: 0x777,
-                    hardlinks: 1,
-                    uid: parent_attrs.uid,
-                    gid: parent_attrs.gid,
-                    md5: "".to_string()
-                };
-                self.write_inode(&attrs);
-                let mut entries = BTreeMap::new();
-                entries.insert(b"..".to_vec(), (parent, FileKind::Directory));
-                entries.insert(b".".to_vec(), (dir_inode, FileKind::Directory));
-                self.write_directory_content(dir_inode, entries);
-
-                let mut parent_entries = self.get_directory_content(parent).unwrap();
-                let name = list_directory(dir_path.as_str()).unwrap();
-                parent_entries.insert(name.as_bytes().to_vec(), (dir_inode, FileKind::Directory));
-                self.write_directory_content(parent, parent_entries);
-                parent_attrs.last_modified = time_now();
-                parent_attrs.last_metadata_changed = time_now();
-                self.write_inode(&parent_attrs);
-                self.init_directories(&dir_path, dir_inode).await?;
-            }
+    // Give a content file shared through the dedup store its own copy before it's modified
+    fn unshare_content(&self, inode: Inode) -> io::Result<()> {
+        let path = match self.cache.file_path(inode) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.nlink() > 1 => {}
+            _ => return Ok(()),
+        }
+        let copy = path.with_extension("unshared");
+        fs::copy(&path, &copy)?;
+        fs::rename(&copy, &path)?;
+        if let Ok(attrs) = self.get_inode(inode) {
+            self.release_dedup_object(&attrs.md5);
         }
         Ok(())
     }
 
-    // Check whether a file should be removed from storage. Should be called after decrementing
-    // the link count, or closing a file handle
-    fn gc_inode(&self, inode: &InodeAttributes) -> bool {
-        if inode.hardlinks == 0 && inode.open_file_handles == 0 {
-            let inode_path = Path::new(&self.data_dir)
-                .join("inodes")
-                .join(inode.inode.to_string());
-            fs::remove_file(inode_path).unwrap();
-            let content_path = Path::new(&self.data_dir)
-                .join("contents")
-                .join(inode.inode.to_string());
-            fs::remove_file(content_path).unwrap();
-
-            return true;
+    fn copy_through_cache(
+        &self,
+        src_inode: Inode,
+        src_offset: u64,
+        dest_inode: Inode,
+        dest_offset: u64,
+        len: u64,
+    ) -> io::Result<u64> {
+        let mut buffer = vec![0; min(len, COPY_BUFFER_SIZE) as usize];
+        let mut copied: u64 = 0;
+        while copied < len {
+            let chunk = min(len - copied, buffer.len() as u64) as usize;
+            let read = self.cache.read_range(src_inode, src_offset + copied, &mut buffer[..chunk])?;
+            if read == 0 {
+                break;
+            }
+            self.cache.write_range(dest_inode, dest_offset + copied, &buffer[..read])?;
+            copied += read as u64;
         }
+        Ok(copied)
+    }
 
-        return false;
+    // Remove a dedup store entry once no inode links to it anymore
+    fn release_dedup_object(&self, md5: &str) {
+        if md5.is_empty() {
+            return;
+        }
+        let object = self.dedup_path(md5);
+        if matches!(fs::metadata(&object), Ok(metadata) if metadata.nlink() == 1) {
+            let _ = fs::remove_file(object);
+        }
     }
 
-}
+    // Report the blocks actually allocated by the cached contents, so du sees through sparse
+    // and partially cached files, and fall back to the apparent size when nothing is cached
+    fn file_attr(&self, attrs: InodeAttributes) -> fuser::FileAttr {
+        let blocks = self.cache.allocated_blocks(attrs.inode);
+        let mut attr: fuser::FileAttr = attrs.into();
+        if let Some(blocks) = blocks {
+            attr.blocks = blocks;
+        }
+        attr
+    }
 
+    // A symbolic link's target is kept in its content file, like a directory's descriptor
+    fn symlink_path(&self, inode: Inode) -> PathBuf {
+        Path::new(&self.data_dir)
+            .join("contents")
+            .join(inode.to_string())
+    }
 
-impl Filesystem for S3FS {
+    // Upload a file's cached contents to its object, then take the md5 and mtime the bucket
+    // reports so the next open sees the cache as up to date
+    fn upload_content(&self, attrs: &mut InodeAttributes) -> Result<(), c_int> {
+        if self.options.append_segments {
+            return self.upload_segment(attrs);
+        }
+        let filename = self.get_filename_from_inode(attrs.inode)?;
+        // Every name of the file is its own object, they are all brought up to date
+        let other_keys: Vec<String> = if attrs.hardlinks > 1 {
+            self.linked_keys(attrs.inode, false)?.into_iter().filter(|key| *key != filename).collect()
+        } else {
+            vec![]
+        };
+        let rt = Runtime::new().unwrap();
+        let metadata = rt
+            .block_on(async {
+                if self.options.hardlink_policy == HardlinkPolicy::All {
+                    for key in &other_keys {
+                        self.put_content(key, attrs.inode, attrs.size).await?;
+                    }
+                }
+                self.put_content(&filename, attrs.inode, attrs.size).await?;
+                if self.options.hardlink_policy == HardlinkPolicy::Canonical {
+                    for key in &other_keys {
+                        self.worker.copy(&filename, key).await?;
+                    }
+                }
+                self.worker.get_stats(&filename).await
+            })
+            .map_err(|e| {
+                error!("Unable to upload {}: {}", filename, e);
+                e.errno()
+            })?;
+        attrs.md5 = object_version(&metadata);
+        attrs.dirty = false;
+        attrs.last_modified = time_from_offsetdatatime(metadata.last_modified());
+        self.pending_uploads.lock().unwrap().remove(&attrs.inode);
+        self.write_inode(attrs)
+    }
+
+    // Upload a file's cached contents to `key`. Cache files are streamed in parts, so large files
+    // never sit in memory whole.
+    async fn put_content(&self, key: &str, inode: Inode, size: u64) -> WorkerResult<()> {
+        let headers = object_headers(&self.get_inode(inode).map_err(io::Error::from_raw_os_error)?.xattrs);
+        if let Some(path) = self.cache.file_path(inode) {
+            return self.worker.put_file(key, path.to_str().unwrap(), size, &headers).await;
+        }
+        let mut data = vec![0; size as usize];
+        self.cache.read_range(inode, 0, &mut data)?;
+        self.worker.put_data_with(key, data, &headers).await
+    }
+
+    // Keys of the names of a file, in the order the mount met them. A key is built from its
+    // directory's prefix, so renaming a directory leaves the names below it valid. A file the
+    // mount hasn't met yet, e.g. a dirty file uploaded right after a remount, is searched for
+    // in the directories listed so far. `first_only` stops at the first name.
+    fn linked_keys(&self, inode: Inode, first_only: bool) -> Result<Vec<String>, c_int> {
+        let mut links = self.current_names(inode);
+        let complete = if first_only {
+            !links.is_empty()
+        } else {
+            links.len() >= self.get_inode(inode)?.hardlinks as usize
+        };
+        if !complete {
+            for (parent, name) in self.find_names(inode, first_only)? {
+                self.add_name(inode, parent, &name);
+            }
+            links = self.current_names(inode);
+        }
+        if first_only {
+            links.truncate(1);
+        }
+        links
+            .iter()
+            .map(|(parent, name)| Ok(format!("{}{}", self.directory_key(*parent)?, String::from_utf8_lossy(name))))
+            .collect()
+    }
+
+    // Record that `name` in directory `parent` links to `inode`
+    fn add_name(&self, inode: Inode, parent: Inode, name: &[u8]) {
+        let mut names = self.names.lock().unwrap();
+        let links = names.entry(inode).or_default();
+        if !links.iter().any(|(directory, known)| *directory == parent && known == name) {
+            links.push((parent, name.to_vec()));
+        }
+    }
+
+    fn remove_name(&self, inode: Inode, parent: Inode, name: &[u8]) {
+        if let Some(links) = self.names.lock().unwrap().get_mut(&inode) {
+            links.retain(|(directory, known)| !(*directory == parent && known == name));
+        }
+    }
+
+    // The recorded names of a file that its directory still lists it under. Directories
+    // rebuilt from a new listing may have dropped or renumbered an entry.
+    fn current_names(&self, inode: Inode) -> Vec<Link> {
+        let links = self.names.lock().unwrap().get(&inode).cloned().unwrap_or_default();
+        let mut current = Vec::new();
+        for (parent, name) in links {
+            match self.get_directory_content(parent) {
+                Ok(entries) if entries.get(&name).map(|(entry, _)| *entry) == Some(inode) => current.push((parent, name)),
+                _ => self.remove_name(inode, parent, &name),
+            }
+        }
+        current
+    }
+
+    // Search the directories listed so far, breadth first, for the names of a file
+    fn find_names(&self, inode: Inode, first_only: bool) -> Result<Vec<Link>, c_int> {
+        let mut links = Vec::new();
+        let mut directories = VecDeque::from([FUSE_ROOT_ID]);
+        while let Some(directory) = directories.pop_front() {
+            for (name, (entry, kind)) in self.directory_entries(directory)? {
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                match kind {
+                    FileKind::Directory => directories.push_back(entry),
+                    _ if entry == inode => {
+                        links.push((directory, name));
+                        if first_only {
+                            return Ok(links);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(links)
+    }
+
+    // Upload the bytes appended since the last upload as the file's next segment. Nothing that
+    // was uploaded before is written again, so the bucket may refuse overwrites.
+    fn upload_segment(&self, attrs: &mut InodeAttributes) -> Result<(), c_int> {
+        let filename = self.get_filename_from_inode(attrs.inode)?;
+        let uploaded: u64 = attrs.segments.iter().sum();
+        let mut data = vec![0; attrs.size.saturating_sub(uploaded) as usize];
+        if let Err(e) = self.cache.read_range(attrs.inode, uploaded, &mut data) {
+            error!("Unable to read inode {}: {}", attrs.inode, e);
+            return Err(libc::EIO);
+        }
+        if !data.is_empty() {
+            let key = segment_key(&filename, attrs.segments.len());
+            let len = data.len() as u64;
+            let rt = Runtime::new().unwrap();
+            rt.block_on(self.worker.put_data(&key, data)).map_err(|e| {
+                error!("Unable to upload {}: {}", key, e);
+                e.errno()
+            })?;
+            attrs.segments.push(len);
+        }
+        // There is no object md5 for the whole file, the segment count marks the cache as current
+        attrs.md5 = segments_marker(attrs.segments.len());
+        attrs.dirty = false;
+        self.pending_uploads.lock().unwrap().remove(&attrs.inode);
+        self.write_inode(attrs)
+    }
+
+    // Download every segment of a file into the cache, in order
+    fn fetch_segments(&self, rt: &Runtime, key: &str, attrs: &InodeAttributes) -> WorkerResult<u64> {
+        self.cache.truncate(attrs.inode, 0)?;
+        let mut offset = 0;
+        for (index, len) in attrs.segments.iter().enumerate() {
+            let data = rt.block_on(self.read_object_range(&segment_key(key, index), 0, *len))?;
+            self.cache.write_range(attrs.inode, offset, &data)?;
+            offset += data.len() as u64;
+        }
+        Ok(offset)
+    }
+
+    // Uploaded segments can't change, so in --append-segments mode only writes past them are allowed
+    fn check_append_only(&self, inode: Inode, offset: u64) -> Result<(), c_int> {
+        if !self.options.append_segments {
+            return Ok(());
+        }
+        if offset < self.get_inode(inode)?.segments.iter().sum() {
+            return Err(libc::EPERM);
+        }
+        Ok(())
+    }
+
+    // Keep the bytes waiting for upload under the writeback high-water mark. Once a write would
+    // cross it, files with local changes are uploaded, largest first, until the pending bytes
+    // are back under the low-water mark. Non-blocking handles get EAGAIN instead of waiting.
+    fn throttle_writes(&self, incoming: u64, flags: i32) -> Result<(), c_int> {
+        let high_water = match self.options.limits.writeback_high_water() {
+            Some(high_water) => high_water,
+            None => return Ok(()),
+        };
+        let mut queued: Vec<_> = self.pending_uploads.lock().unwrap().iter().map(|(inode, size)| (*inode, *size)).collect();
+        let mut pending: u64 = queued.iter().map(|(_, size)| size).sum();
+        if pending + incoming <= high_water {
+            return Ok(());
+        }
+        if flags & libc::O_NONBLOCK != 0 {
+            return Err(libc::EAGAIN);
+        }
+        let low_water = self.options.limits.writeback_low_water();
+        debug!("{} bytes waiting for upload, draining to {}", pending, low_water);
+        queued.sort_by_key(|(_, size)| Reverse(*size));
+        for (inode, size) in queued {
+            if pending <= low_water {
+                break;
+            }
+            match self.get_inode(inode) {
+                Ok(mut attrs) if attrs.dirty && attrs.hardlinks > 0 => self.upload_content(&mut attrs)?,
+                // Uploaded, downloaded again or removed in the meantime
+                _ => {
+                    self.pending_uploads.lock().unwrap().remove(&inode);
+                }
+            }
+            pending = pending.saturating_sub(size);
+        }
+        Ok(())
+    }
+
+    // Key of the object behind a file, the first name the mount met it under when it has several
+    fn get_filename_from_inode(&self, inode: Inode) -> Result<String, c_int> {
+        if let Some(key) = &self.options.single_object {
+            return Ok(key.clone());
+        }
+        self.linked_keys(inode, true)?.pop().ok_or(libc::ENOENT)
+    }
+
+    // A changed user.s3.* header only reaches the bucket with the object's next upload, cached
+    // files are marked as changed so they get one
+    fn mark_header_change(&self, attrs: &mut InodeAttributes) {
+        if self.cache.cached_len(attrs.inode).is_some() && attrs.md5 != PARTIAL_MARKER {
+            attrs.dirty = true;
+        }
+    }
+
+    // Fail with ELOOP when resolving the symbolic link `inode` takes more than
+    // --max-symlink-depth links, e.g. because it is part of a cycle
+    fn check_symlink_depth(&self, inode: Inode) -> Result<(), c_int> {
+        let key = self.linked_keys(inode, true)?.pop().ok_or(libc::ENOENT)?;
+        walk_symlinks(key.as_bytes(), self.options.max_symlink_depth, |dir, name| {
+            let attrs = self.lookup_name(dir, OsStr::from_bytes(name))?;
+            if attrs.kind != FileKind::Symlink {
+                return Ok((attrs.inode, None));
+            }
+            let target = fs::read(self.symlink_path(attrs.inode)).map_err(|_| libc::EIO)?;
+            Ok((attrs.inode, Some(target)))
+        })
+    }
+
+    fn creation_mode(&self, mode: u32) -> u16 {
+        (mode & !(libc::S_ISUID | libc::S_ISGID) as u32) as u16
+    }
+
+    fn is_immutable(&self, key: &str) -> bool {
+        self.options.immutable_globs.iter().any(|pattern| pattern.matches(key))
+    }
+
+    // Resolve "<name>.s3meta" to the virtual metadata file of the regular file "<name>"
+    fn lookup_meta_file(&self, parent: u64, name: &OsStr) -> Result<InodeAttributes, c_int> {
+        let object_name = match name.as_bytes().strip_suffix(META_FILE_SUFFIX) {
+            Some(object_name) if self.options.show_meta_files => object_name,
+            _ => return Err(libc::ENOENT),
+        };
+        let attrs = self.lookup_name(parent, OsStr::from_bytes(object_name))?;
+        if attrs.kind != FileKind::File {
+            return Err(libc::ENOENT);
+        }
+        self.get_meta_file_attrs(attrs.inode | META_INODE_BIT)
+    }
+
+    // Render the backend metadata of an object as JSON, generated on demand from get_stats
+    fn meta_file_content(&self, inode: Inode) -> Result<Vec<u8>, c_int> {
+        let filename = self.get_filename_from_inode(inode & !META_INODE_BIT)?;
+        let rt = Runtime::new().unwrap();
+        let metadata = rt.block_on(self.worker.get_stats(&filename)).map_err(|e| e.errno())?;
+        let json = serde_json::json!({
+            "key": filename,
+            "content_length": metadata.content_length(),
+            "content_md5": metadata.content_md5(),
+            "content_type": metadata.content_type(),
+            "etag": metadata.etag(),
+            "last_modified": metadata.last_modified().map(|dt| dt.unix_timestamp()),
+        });
+        Ok(serde_json::to_vec_pretty(&json).unwrap())
+    }
+
+    fn get_meta_file_attrs(&self, inode: Inode) -> Result<InodeAttributes, c_int> {
+        let mut attrs = self.get_inode(inode & !META_INODE_BIT)?;
+        attrs.inode = inode;
+        attrs.size = self.meta_file_content(inode)?.len() as u64;
+        attrs.mode = 0o444;
+        attrs.hardlinks = 1;
+        Ok(attrs)
+    }
+
+    // List a prefix in the primary bucket merged with the origin bucket, if there is one
+    async fn list_backend(&self, path: &str) -> WorkerResult<Vec<String>> {
+        let mut names = self.worker.list_dir(path).await?;
+        if let Some(origin) = &self.origin {
+            for name in origin.list_dir(path).await? {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        // Some services list a folder's own marker object under its prefix
+        names.retain(|name| !name.is_empty() && name != "/" && !(self.keep_markers && *name == self.options.keep_marker));
+        Ok(names)
+    }
+
+    // Store the object that keeps the directory `key` ("a/b/") listed. That is a zero-byte
+    // marker, unless the backend refuses one while it takes other objects in the directory.
+    // Then this and every later directory is kept with a --keep-marker object.
+    fn create_dir_marker(&mut self, key: &str) -> WorkerResult<()> {
+        let keep = format!("{}{}", key, self.options.keep_marker);
+        let rt = Runtime::new().unwrap();
+        if self.keep_markers {
+            return rt.block_on(self.worker.put_data(&keep, Vec::new()));
+        }
+        let refused = match rt.block_on(self.worker.create_dir(key)) {
+            Err(e @ WorkerError::Other(_)) => e,
+            result => return result,
+        };
+        // Any other failure of the bucket fails this upload too
+        if rt.block_on(self.worker.put_data(&keep, Vec::new())).is_err() {
+            return Err(refused);
+        }
+        warn!("The bucket refuses directory markers ({}), keeping directories with {} objects", refused, keep);
+        self.keep_markers = true;
+        Ok(())
+    }
+
+    // Stat an object in the primary bucket, falling back to the origin bucket on a miss.
+    // Also returns the worker holding the object, to download it from.
+    async fn stat_object(&self, path: &str) -> WorkerResult<(Metadata, &dyn StorageBackend)> {
+        let origin = match &self.origin {
+            Some(origin) => origin.as_ref(),
+            None => return Ok((self.worker.get_stats(path).await?, self.worker.as_ref())),
+        };
+        match self.worker.get_stats(path).await {
+            Err(WorkerError::NotFound(_)) => {}
+            result => return Ok((result?, self.worker.as_ref())),
+        }
+        Ok((origin.get_stats(path).await?, origin))
+    }
+
+    // Read part of an object from the primary bucket, falling back to the origin bucket on a miss
+    async fn read_object_range(&self, path: &str, offset: u64, len: u64) -> WorkerResult<Vec<u8>> {
+        let origin = match &self.origin {
+            Some(origin) => origin,
+            None => return self.worker.read_range(path, offset, len).await,
+        };
+        match self.worker.read_range(path, offset, len).await {
+            Err(WorkerError::NotFound(_)) => {}
+            result => return result,
+        }
+        origin.read_range(path, offset, len).await
+    }
+
+    // Whether an object can't fit in the cache, even once the inode's current contents are dropped
+    fn is_oversize(&self, inode: Inode, size: u64) -> bool {
+        match self.cache.available_space() {
+            Some(free) => size > free + self.cache.cached_len(inode).unwrap_or(0),
+            None => false,
+        }
+    }
+
+    // Whether open may leave fetching an object to reads, with --readahead-blocks or
+    // --stream-reads. Objects within one block take a single request either way, and objects
+    // filled into the primary bucket from the origin are needed whole.
+    fn fetches_on_read(&self, write: bool, source: &dyn StorageBackend, size: u64) -> bool {
+        !write
+            && size > self.worker.download_options().block_size
+            && (!self.options.origin_fill || same_backend(source, self.worker.as_ref()))
+    }
+
+    // Make sure the blocks holding `len` bytes at `offset` of a file read block by block are
+    // cached, taking the finished prefetches and downloading what is still missing. The file
+    // is an ordinary cached file again once all of its blocks are there.
+    fn fetch_blocks(&mut self, inode: Inode, offset: u64, len: u64) -> Result<(), c_int> {
+        let block_size = self.worker.download_options().block_size;
+        let partial = match self.partial.get_mut(&inode) {
+            Some(partial) => partial,
+            None => return Ok(()),
+        };
+        let finished: Vec<(u64, Vec<u8>)> = {
+            let mut prefetched = partial.prefetched.lock().unwrap();
+            let blocks: Vec<u64> = prefetched
+                .iter()
+                .filter(|(_, data)| data.is_some())
+                .map(|(block, _)| *block)
+                .collect();
+            blocks
+                .into_iter()
+                .filter_map(|block| prefetched.remove(&block).flatten().map(|data| (block, data)))
+                .collect()
+        };
+        let mut missing = vec![];
+        if len > 0 {
+            missing = (offset / block_size..=(offset + len - 1) / block_size)
+                .filter(|block| !partial.fetched.contains(block) && !finished.iter().any(|(done, _)| done == block))
+                .collect();
+        }
+        let source = match (&self.origin, partial.from_origin) {
+            (Some(origin), true) => origin.as_ref(),
+            _ => self.worker.as_ref(),
+        };
+        let size = partial.size;
+        let key = &partial.key;
+        let downloaded = Runtime::new()
+            .unwrap()
+            .block_on(futures::future::try_join_all(missing.into_iter().map(|block| async move {
+                let start = block * block_size;
+                let data = source.read_range(key, start, min(block_size, size - start)).await?;
+                Ok::<_, WorkerError>((block, data))
+            })))
+            .map_err(|e| {
+                error!("Unable to read {}: {}", key, e);
+                e.errno()
+            })?;
+        for (block, data) in finished.into_iter().chain(downloaded) {
+            // A block prefetched while it was also read directly is already there
+            if !partial.fetched.insert(block) {
+                continue;
+            }
+            if let Err(e) = self.cache.write_range(inode, block * block_size, &data) {
+                error!("Unable to cache inode {}: {}", inode, e);
+                partial.fetched.remove(&block);
+                return Err(libc::EIO);
+            }
+        }
+        if (partial.fetched.len() as u64) < size / block_size + (size % block_size != 0) as u64 {
+            return Ok(());
+        }
+        let md5 = match self.partial.remove(&inode) {
+            Some(partial) => partial.md5,
+            None => return Ok(()),
+        };
+        debug!("Every block of inode {} is cached", inode);
+        let mut attrs = self.get_inode(inode)?;
+        attrs.md5 = md5;
+        self.write_inode(&attrs)?;
+        self.enforce_cache_size(inode);
+        Ok(())
+    }
+
+    // After a sequential read of a file read block by block, start downloading the blocks
+    // following it in the background. fetch_blocks picks them up once they are done.
+    fn prefetch_blocks(&mut self, inode: Inode, fh: u64, offset: u64, len: u64) {
+        let block_size = self.worker.download_options().block_size;
+        let count = self.options.readahead_blocks.unwrap_or(0) as u64;
+        let partial = match self.partial.get_mut(&inode) {
+            Some(partial) => partial,
+            None => return,
+        };
+        // A handle's first read counts as sequential when it starts at the beginning
+        let sequential = partial.read_ends.insert(fh, offset + len).unwrap_or(0) == offset;
+        if !sequential || len == 0 || count == 0 {
+            return;
+        }
+        let num_blocks = partial.size / block_size + (partial.size % block_size != 0) as u64;
+        let next = (offset + len - 1) / block_size + 1;
+        let blocks: Vec<u64> = {
+            let mut prefetched = partial.prefetched.lock().unwrap();
+            let blocks: Vec<u64> = (next..min(next + count, num_blocks))
+                .filter(|block| !partial.fetched.contains(block) && !prefetched.contains_key(block))
+                .collect();
+            for block in &blocks {
+                prefetched.insert(*block, None);
+            }
+            blocks
+        };
+        if blocks.is_empty() {
+            return;
+        }
+        let source = match (&self.origin, partial.from_origin) {
+            (Some(origin), true) => origin.as_ref(),
+            _ => self.worker.as_ref(),
+        };
+        let (op, key) = (source.operator().clone(), source.object_key(&partial.key));
+        let (size, prefetched) = (partial.size, partial.prefetched.clone());
+        thread::spawn(move || {
+            let rt = Runtime::new().unwrap();
+            rt.block_on(futures::future::join_all(blocks.into_iter().map(|block| {
+                let (op, key, prefetched) = (&op, &key, &prefetched);
+                async move {
+                    let start = block * block_size;
+                    let result = op.range_read(key, start..min(start + block_size, size)).await;
+                    let mut prefetched = prefetched.lock().unwrap();
+                    match result {
+                        Ok(data) => {
+                            prefetched.insert(block, Some(data));
+                        }
+                        Err(e) => {
+                            // Reading the block fetches it again
+                            warn!("Unable to prefetch block {} of {}: {}", block, key, e);
+                            prefetched.remove(&block);
+                        }
+                    }
+                }
+            })));
+        });
+    }
+
+    // Read a streamed file through the reader its handle keeps open, so sequential reads carry on
+    // with one request instead of making a range request each. A read anywhere else opens a new
+    // reader at its offset, running to the end of the object.
+    fn stream_read(&mut self, fh: u64, key: &str, offset: u64, len: u64) -> WorkerResult<Vec<u8>> {
+        let rt = match &self.stream_runtime {
+            Some(rt) => rt,
+            None => return Err(WorkerError::Other("stream reads are disabled".to_string())),
+        };
+        let mut reader = match self.readers.remove(&fh) {
+            Some((position, reader)) if position == offset => reader,
+            _ => rt.block_on(async {
+                let (metadata, source) = self.stat_object(key).await?;
+                let range = offset..metadata.content_length().max(offset);
+                Ok::<_, WorkerError>(source.operator().range_reader(&source.object_key(key), range).await?)
+            })?,
+        };
+        let mut data = vec![0; len as usize];
+        let mut filled = 0;
+        while filled < data.len() {
+            match rt.block_on(reader.read(&mut data[filled..]))? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        data.truncate(filled);
+        self.readers.insert(fh, (offset + filled as u64, reader));
+        Ok(data)
+    }
+
+    // List the backend prefix `path` into the directory `parent`, materializing `depth` levels
+    // below it (all of them when None). Subdirectories beyond that are left unexpanded and get
+    // listed on first access.
+    #[async_recursion]
+    async fn init_directories(&self, path: &str, parent: Inode, depth: Option<u32>)  -> WorkerResult<()>{
+        let entries = self.list_backend(path).await?;
+        let mut parent_attrs = self.get_inode(parent).map_err(io::Error::from_raw_os_error)?;
+        // Children are collected in memory so every inode and descriptor is written exactly
+        // once, instead of rewriting the parent for each child
+        let mut parent_entries = self.get_directory_content(parent).map_err(io::Error::from_raw_os_error)?;
+        let mut subdirectories = Vec::new();
+        let first_inode = self.allocate_inodes(entries.len() as u64).map_err(io::Error::from_raw_os_error)?;
+        for (inode, file) in (first_inode..).zip(entries) {
+            let full_path = format!("{}{}", path, file);
+            if let Some(name) = file.strip_suffix(SEGMENTS_SUFFIX).filter(|_| self.options.append_segments) {
+                let attrs = self.init_segmented_file(&format!("{}{}", path, name), inode, &parent_attrs).await?;
+                parent_entries.insert(name.as_bytes().to_vec(), (inode, attrs.kind));
+                continue;
+            }
+            // A key ending in "/" is a folder marker, its zero-byte object becomes a directory
+            // instead of an empty file with a slash in its name
+            let metadata = if file.ends_with('/') {
+                None
+            } else {
+                Some(self.stat_object(&full_path).await?.0)
+            };
+
+            if let Some(metadata) = metadata.filter(|metadata| metadata.is_file() || metadata.content_type() == Some(SYMLINK_CONTENT_TYPE)) {
+                let attrs = self.init_object(&full_path, inode, &parent_attrs, &metadata).await?;
+                parent_entries.insert(file.as_bytes().to_vec(), (inode, attrs.kind));
+            } else {
+                let dir_path = format!("{}/", full_path.trim_end_matches('/'));
+                let expand = !matches!(depth, Some(depth) if depth <= 1);
+                let attrs = InodeAttributes {
+                    inode,
+                    open_file_handles: 1,
+                    size: 0,
+                    last_accessed: time_now(),
+                    last_modified: time_now(),
+                    last_metadata_changed: time_now(),
+                    created: time_now(),
+                    kind: FileKind::Directory,
+                    mode: 0x777,
+                    hardlinks: 1,
+                    uid: parent_attrs.uid,
+                    gid: parent_attrs.gid,
+                    md5: "".to_string(),
+                    expanded: false,
+                    xattrs: BTreeMap::new(),
+                    segments: Vec::new(),
+                    dirty: false,
+                };
+                self.write_inode(&attrs).map_err(io::Error::from_raw_os_error)?;
+                let mut entries = BTreeMap::new();
+                entries.insert(b"..".to_vec(), (parent, FileKind::Directory));
+                entries.insert(b".".to_vec(), (inode, FileKind::Directory));
+                self.write_directory_content(inode, entries).map_err(io::Error::from_raw_os_error)?;
+
+                let name = list_directory(dir_path.as_str()).unwrap();
+                parent_entries.insert(name.as_bytes().to_vec(), (inode, FileKind::Directory));
+                if expand {
+                    subdirectories.push((dir_path, inode));
+                }
+            }
+        }
+        self.write_directory_content(parent, parent_entries).map_err(io::Error::from_raw_os_error)?;
+        // Listing the bucket doesn't change the directory, so lazily expanding it on first
+        // access leaves its times alone
+        parent_attrs.expanded = true;
+        self.write_inode(&parent_attrs).map_err(io::Error::from_raw_os_error)?;
+
+        for (dir_path, dir_inode) in subdirectories {
+            self.init_directories(&dir_path, dir_inode, depth.map(|depth| depth - 1)).await?;
+        }
+        Ok(())
+    }
+
+    // Create the inode of a discovered object: a symbolic link when it carries the symlink
+    // content type, a regular file otherwise
+    async fn init_object(&self, key: &str, inode: Inode, parent: &InodeAttributes, metadata: &Metadata) -> WorkerResult<InodeAttributes> {
+        if metadata.content_type() == Some(SYMLINK_CONTENT_TYPE) {
+            let target = self.read_object_range(key, 0, metadata.content_length()).await?;
+            let attrs = InodeAttributes {
+                inode,
+                open_file_handles: 0,
+                size: target.len() as u64,
+                last_accessed: time_now(),
+                last_modified: time_from_offsetdatatime(metadata.last_modified()),
+                last_metadata_changed: time_now(),
+                created: time_from_offsetdatatime(metadata.last_modified()),
+                kind: FileKind::Symlink,
+                mode: 0o777,
+                hardlinks: 1,
+                uid: parent.uid,
+                gid: parent.gid,
+                md5: "".to_string(),
+                expanded: true,
+                xattrs: BTreeMap::new(),
+                segments: Vec::new(),
+                dirty: false,
+            };
+            self.write_inode(&attrs).map_err(io::Error::from_raw_os_error)?;
+            fs::write(self.symlink_path(inode), target)?;
+            return Ok(attrs);
+        }
+        // Size and mtime come from the object so stat is right before the file is
+        // ever opened, open still downloads it since nothing is cached yet
+        let mut attrs = InodeAttributes {
+            inode,
+            open_file_handles: 0,
+            size: metadata.content_length(),
+            last_accessed: time_now(),
+            last_modified: time_from_offsetdatatime(metadata.last_modified()),
+            last_metadata_changed: time_now(),
+            created: time_from_offsetdatatime(metadata.last_modified()),
+            kind: FileKind::File,
+            mode: 0x777,
+            hardlinks: 1,
+            uid: parent.uid,
+            gid: parent.gid,
+            md5: "".to_string(),
+            expanded: true,
+            xattrs: BTreeMap::new(),
+            segments: Vec::new(),
+            dirty: false,
+        };
+        load_object_headers(&mut attrs.xattrs, metadata);
+        self.write_inode(&attrs).map_err(io::Error::from_raw_os_error)?;
+        Ok(attrs)
+    }
+
+    // Create the inode of a file stored as append segments, sized by the segments found
+    async fn init_segmented_file(&self, key: &str, inode: Inode, parent: &InodeAttributes) -> WorkerResult<InodeAttributes> {
+        let prefix = format!("{}{}", key, SEGMENTS_SUFFIX);
+        let mut names = self.list_backend(&prefix).await?;
+        names.sort();
+        let mut segments = Vec::with_capacity(names.len());
+        let mut last_modified = None;
+        for name in names {
+            let (metadata, _) = self.stat_object(&format!("{}{}", prefix, name)).await?;
+            segments.push(metadata.content_length());
+            last_modified = metadata.last_modified();
+        }
+        let attrs = InodeAttributes {
+            inode,
+            open_file_handles: 0,
+            size: segments.iter().sum(),
+            last_accessed: time_now(),
+            last_modified: time_from_offsetdatatime(last_modified),
+            last_metadata_changed: time_now(),
+            created: time_from_offsetdatatime(last_modified),
+            kind: FileKind::File,
+            mode: 0x777,
+            hardlinks: 1,
+            uid: parent.uid,
+            gid: parent.gid,
+            md5: "".to_string(),
+            expanded: true,
+            xattrs: BTreeMap::new(),
+            segments,
+            dirty: false,
+        };
+        self.write_inode(&attrs).map_err(io::Error::from_raw_os_error)?;
+        Ok(attrs)
+    }
+
+    // Make the object `key` the only entry of the root, under its last path component
+    async fn init_single_object(&self, key: &str) -> WorkerResult<()> {
+        let (metadata, _) = self.stat_object(key).await?;
+        let mut root = self.get_inode(FUSE_ROOT_ID).map_err(io::Error::from_raw_os_error)?;
+        let inode = self.allocate_next_inode().map_err(io::Error::from_raw_os_error)?;
+        let attrs = InodeAttributes {
+            inode,
+            open_file_handles: 0,
+            size: metadata.content_length(),
+            last_accessed: time_now(),
+            last_modified: time_from_offsetdatatime(metadata.last_modified()),
+            last_metadata_changed: time_now(),
+            created: time_from_offsetdatatime(metadata.last_modified()),
+            kind: FileKind::File,
+            mode: 0o666,
+            hardlinks: 1,
+            uid: root.uid,
+            gid: root.gid,
+            md5: "".to_string(),
+            expanded: true,
+            xattrs: BTreeMap::new(),
+            segments: Vec::new(),
+            dirty: false,
+        };
+        self.write_inode(&attrs).map_err(io::Error::from_raw_os_error)?;
+        let name = key.rsplit('/').next().unwrap_or(key);
+        let mut entries = self.get_directory_content(FUSE_ROOT_ID).map_err(io::Error::from_raw_os_error)?;
+        entries.insert(name.as_bytes().to_vec(), (inode, FileKind::File));
+        self.write_directory_content(FUSE_ROOT_ID, entries).map_err(io::Error::from_raw_os_error)?;
+        root.expanded = true;
+        self.write_inode(&root).map_err(io::Error::from_raw_os_error)?;
+        Ok(())
+    }
+
+    // List a directory that discovery left unexpanded, one level at a time
+    fn ensure_expanded(&self, inode: Inode) -> Result<(), c_int> {
+        let attrs = self.get_inode(inode)?;
+        if attrs.kind != FileKind::Directory || attrs.expanded {
+            return Ok(());
+        }
+        let path = self.directory_key(inode)?;
+        let rt = Runtime::new().unwrap();
+        rt.block_on(self.init_directories(&path, inode, Some(1))).map_err(|e| {
+            error!("Unable to list {}: {}", path, e);
+            e.errno()
+        })
+    }
+
+    // Stream a directory's entries in name order. Names like "-x" sort before "." in byte order,
+    // so the dot entries are moved to the front to take the first offsets, as in every other
+    // order. A first pass finds them and stops at the first name above "..".
+    fn name_ordered_listing(&self, inode: Inode) -> Result<DirectoryListing, c_int> {
+        let is_dot = |name: &[u8]| name == b"." || name == b"..";
+        let dots: Vec<_> = self
+            .directory_entries(inode)?
+            .take_while(|(name, _)| name.as_slice() <= b"..".as_ref())
+            .filter(|(name, _)| is_dot(name))
+            .collect();
+        let rest = self.directory_entries(inode)?.filter(move |(name, _)| !is_dot(name));
+        Ok(Box::new(
+            dots.into_iter().chain(rest).map(|(name, (inode, file_type))| (name, inode, file_type)),
+        ))
+    }
+
+    fn sort_listing(&self, listing: DirectoryListing) -> DirectoryListing {
+        let mut entries: Vec<_> = listing.collect();
+        entries.sort_by_cached_key(|(name, inode, _)| {
+            let is_dot = name == b"." || name == b"..";
+            // A metadata file sorts by the file it describes
+            let key = match self.get_inode(inode & !META_INODE_BIT) {
+                Ok(attrs) if self.options.readdir_order == ReaddirOrder::Mtime => attrs.last_modified,
+                Ok(attrs) => (attrs.size as i64, 0),
+                Err(_) => (i64::MIN, 0),
+            };
+            (!is_dot, Reverse(key), name.clone())
+        });
+        Box::new(entries.into_iter())
+    }
+
+    // Sum the sizes of all files below a directory by walking the descriptors. Directories
+    // that were never listed from the bucket count as empty.
+    fn tree_size(&self, inode: Inode) -> Result<u64, c_int> {
+        let mut total = 0;
+        let mut pending = vec![inode];
+        while let Some(directory) = pending.pop() {
+            for (name, (child, kind)) in self.get_directory_content(directory)? {
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                match kind {
+                    FileKind::File => total += self.get_inode(child)?.size,
+                    FileKind::Directory => pending.push(child),
+                    FileKind::Symlink => {}
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    // Rebuild a directory's backend prefix ("a/b/", "" for the root) by following ".." entries up to the root
+    fn directory_key(&self, inode: Inode) -> Result<String, c_int> {
+        let mut components = Vec::new();
+        let mut current = inode;
+        while current != FUSE_ROOT_ID {
+            let (parent, _) = *self.get_directory_content(current)?.get(b"..".as_ref()).ok_or(libc::EIO)?;
+            let name = self.get_directory_content(parent)?
+                .into_iter()
+                .find(|(name, (inode, _))| *inode == current && name != b"." && name != b"..")
+                .map(|(name, _)| name)
+                .ok_or(libc::ENOENT)?;
+            components.push(String::from_utf8_lossy(&name).into_owned());
+            current = parent;
+        }
+        Ok(components.iter().rev().map(|name| format!("{}/", name)).collect())
+    }
+
+    // Check whether a file should be removed from storage. Should be called after decrementing
+    // the link count, or closing a file handle
+    fn gc_inode(&self, inode: &InodeAttributes) -> bool {
+        if inode.hardlinks == 0 && inode.open_file_handles == 0 {
+            self.deferred_inodes.lock().unwrap().remove(&inode.inode);
+            self.pending_uploads.lock().unwrap().remove(&inode.inode);
+            self.upload_queue.lock().unwrap().remove(&inode.inode);
+            self.names.lock().unwrap().remove(&inode.inode);
+            let inode_path = Path::new(&self.data_dir)
+                .join("inodes")
+                .join(inode.inode.to_string());
+            // The inode is gone either way, leftovers only waste space
+            if let Err(e) = fs::remove_file(inode_path) {
+                warn!("Unable to remove inode {}: {}", inode.inode, e);
+            }
+            if inode.kind == FileKind::File {
+                if let Err(e) = self.cache.evict(inode.inode) {
+                    warn!("Unable to evict inode {}: {}", inode.inode, e);
+                }
+                self.release_dedup_object(&inode.md5);
+            } else {
+                let content_path = Path::new(&self.data_dir)
+                    .join("contents")
+                    .join(inode.inode.to_string());
+                if let Err(e) = fs::remove_file(content_path) {
+                    warn!("Unable to remove the contents of inode {}: {}", inode.inode, e);
+                }
+            }
+
+            return true;
+        }
+
+        return false;
+    }
+
+}
+
+
+impl Filesystem for S3FS {
     // Initialize filesystem. Called before any other filesystem method. 
     // The kernel module connection can be configured using the KernelConfig object.
     fn init(
         &mut self,
         _req: &Request,
-        #[allow(unused_variables)] config: &mut KernelConfig,
+        config: &mut KernelConfig,
     ) -> Result<(), c_int> {
-        fs::create_dir_all(Path::new(&self.data_dir).join("inodes")).unwrap();
-        fs::create_dir_all(Path::new(&self.data_dir).join("contents")).unwrap();
-        if self.get_inode(FUSE_ROOT_ID).is_err() {
+        // Bigger requests mean fewer round trips through FUSE for large sequential IO
+        if let Some(max_write) = self.options.max_write {
+            if let Err(nearest) = config.set_max_write(max_write) {
+                warn!("max_write {} is not supported, using {}", max_write, nearest);
+                config.set_max_write(nearest).unwrap();
+            }
+        }
+        if self.options.kernel_writeback {
+            if let Err(unsupported) = config.add_capabilities(FUSE_WRITEBACK_CACHE) {
+                warn!("Kernel doesn't support writeback caching (capabilities {:#x})", unsupported);
+            }
+        }
+        if let Some(max_readahead) = self.options.max_readahead {
+            if let Err(nearest) = config.set_max_readahead(max_readahead) {
+                warn!("max_readahead {} is not supported, using {}", max_readahead, nearest);
+                config.set_max_readahead(nearest).unwrap();
+            }
+        }
+        for dir in ["inodes", "contents", "objects"] {
+            fs::create_dir_all(Path::new(&self.data_dir).join(dir)).map_err(|e| {
+                error!("Unable to create {}/{}: {}", self.data_dir, dir, e);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            })?;
+        }
+        let initialized = match self.get_inode(FUSE_ROOT_ID) {
+            Ok(_) => true,
+            Err(libc::ENOENT) => false,
+            Err(_) => {
+                // Earlier layouts are upgraded as they are read, so this is a corrupt root or a
+                // cache written by a newer release. The tree is re-derived from the backend.
+                let aside = self.discard_cache().map_err(|e| {
+                    error!("Unable to move the unreadable cache in {} aside: {}", self.data_dir, e);
+                    libc::EIO
+                })?;
+                warn!(
+                    "The cache in {} is unreadable, it was moved to {} with any changes that weren't uploaded",
+                    self.data_dir,
+                    aside.display()
+                );
+                false
+            }
+        };
+        if !initialized {
             // Initialize with empty filesystem
             let root = InodeAttributes {
                 inode: FUSE_ROOT_ID,
@@ -421,61 +2144,1495 @@ impl Filesystem for S3FS {
                 last_accessed: time_now(),
                 last_modified: time_now(),
                 last_metadata_changed: time_now(),
+                created: time_now(),
                 kind: FileKind::Directory,
                 mode: 0o777,
                 hardlinks: 2,
                 uid: 0,
                 gid: 0,
                 md5: "".to_string(),
+                expanded: false,
+                xattrs: BTreeMap::new(),
+                segments: Vec::new(),
+                dirty: false,
             };
-            self.write_inode(&root);
+            self.write_inode(&root)?;
             let mut entries = BTreeMap::new();
             entries.insert(b".".to_vec(), (FUSE_ROOT_ID, FileKind::Directory));
-            self.write_directory_content(FUSE_ROOT_ID, entries);
-            let rt = Runtime::new().unwrap();
-            rt.block_on(self.init_directories("", FUSE_ROOT_ID)).unwrap();
-            
+            // The root is its own parent, so it lists both dot entries even on an empty bucket
+            entries.insert(b"..".to_vec(), (FUSE_ROOT_ID, FileKind::Directory));
+            self.write_directory_content(FUSE_ROOT_ID, entries)?;
+            if let Some(key) = &self.options.single_object {
+                let rt = Runtime::new().unwrap();
+                if let Err(e) = rt.block_on(self.init_single_object(key)) {
+                    error!("Unable to mount {}: {}", key, e);
+                    return Err(e.errno());
+                }
+            } else if self.options.discovery_depth != Some(0) {
+                // With a depth of 0 even the root is listed lazily, on first access
+                let rt = Runtime::new().unwrap();
+                if let Err(e) = rt.block_on(self.init_directories("", FUSE_ROOT_ID, self.options.discovery_depth)) {
+                    error!("Unable to list bucket: {}", e);
+                    return Err(e.errno());
+                }
+            }
+        } else {
+            self.recover_dirty_sizes();
+            // Left behind by a crash, or by uploads that were still queued or failed at unmount
+            self.upload_dirty_files(None);
+        }
+        // Even without a cache size, a config reload can set one on the live mount
+        self.load_cache_lru();
+        Ok(())
+    }
+
+    // Clean up filesystem. Called on filesystem exit. Inode updates still held in memory are
+    // written out and files with local changes are uploaded, so a remount starts from the
+    // same state as the bucket.
+    fn destroy(&mut self) {
+        self.write_deferred_inodes();
+        // Covers every file still in the upload queue
+        self.upload_dirty_files(self.options.shutdown_grace.map(|grace| Instant::now() + grace));
+    }
+
+    // Look up a directory entry by name and get its attributes.
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.invalidate_if_requested();
+        self.upload_queued_files();
+        // The writeback thread only wanted the queue drained. Requests carry the calling thread's
+        // id rather than the process id, so the reserved name is all there is to check.
+        if parent == FUSE_ROOT_ID && name.as_bytes() == WRITEBACK_WAKEUP_NAME {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        if name.len() > MAX_NAME_LENGTH as usize {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        let parent_attrs = match self.get_inode(parent) {
+            Ok(parent_attrs) => parent_attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if !check_access(
+            parent_attrs.uid,
+            parent_attrs.gid,
+            parent_attrs.mode,
+            req.uid(),
+            req.gid(),
+            libc::X_OK,
+        ) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        match self.lookup_name(parent, name).or_else(|_| self.lookup_meta_file(parent, name)) {
+            Ok(attrs) => reply.entry(&Duration::new(0, 0), &self.file_attr(attrs), 0),
+            Err(error_code) => reply.error(error_code),
+        }
+    }
+
+    // Get file attributes.
+    fn getattr(&mut self, _req: &Request, inode: u64, reply: ReplyAttr) {
+        self.upload_queued_files();
+        let attrs = if inode & META_INODE_BIT != 0 {
+            self.get_meta_file_attrs(inode)
+        } else {
+            self.get_inode(inode)
+        };
+        match attrs {
+            Ok(attrs) => reply.attr(&Duration::new(0, 0), &self.file_attr(attrs)),
+            Err(error_code) => reply.error(error_code),
+        }
+    }
+
+    // Check file access permissions. F_OK only asks whether the inode exists.
+    fn access(&mut self, req: &Request, inode: u64, mask: i32, reply: ReplyEmpty) {
+        debug!("access() called with {:?} {:?}", inode, mask);
+        let attrs = if inode & META_INODE_BIT != 0 {
+            self.get_meta_file_attrs(inode)
+        } else {
+            self.get_inode(inode)
+        };
+        match attrs {
+            Ok(attrs) => {
+                if check_access(attrs.uid, attrs.gid, attrs.mode, req.uid(), req.gid(), mask) {
+                    reply.ok();
+                } else {
+                    reply.error(libc::EACCES);
+                }
+            }
+            Err(error_code) => reply.error(error_code),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr() called with {:?}", inode);
+        if inode & META_INODE_BIT != 0 {
+            reply.error(libc::EROFS);
+            return;
+        }
+        if let Err(error_code) = self.check_writable() {
+            reply.error(error_code);
+            return;
+        }
+        let mut attrs = match self.get_inode(inode) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        if let Some(mode) = mode {
+            debug!("chmod() called with {:?}, {:o}", inode, mode);
+            if req.uid() != 0 && req.uid() != attrs.uid {
+                reply.error(libc::EPERM);
+                return;
+            }
+            // Only root may set SUID or SGID
+            attrs.mode = if req.uid() == 0 { mode as u16 } else { self.creation_mode(mode) };
+            attrs.last_metadata_changed = time_now();
+        }
+
+        if uid.is_some() || gid.is_some() {
+            debug!("chown() called with {:?} {:?} {:?}", inode, uid, gid);
+            if let Some(uid) = uid {
+                // Only root may give a file away, no-op changes by the owner are not an error
+                if req.uid() != 0 && !(uid == attrs.uid && req.uid() == attrs.uid) {
+                    reply.error(libc::EPERM);
+                    return;
+                }
+            }
+            if let Some(gid) = gid {
+                // The owner may only change the group to their own
+                if req.uid() != 0 && (req.uid() != attrs.uid || (gid != req.gid() && gid != attrs.gid)) {
+                    reply.error(libc::EPERM);
+                    return;
+                }
+            }
+            if attrs.mode & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) as u16 != 0 {
+                // SUID & SGID are suppose to be cleared when chown'ing an executable file
+                clear_suid_sgid(&mut attrs);
+            }
+            if let Some(uid) = uid {
+                attrs.uid = uid;
+            }
+            if let Some(gid) = gid {
+                attrs.gid = gid;
+            }
+            attrs.last_metadata_changed = time_now();
+        }
+
+        if let Some(size) = size {
+            debug!("truncate() called with {:?} {:?}", inode, size);
+            if attrs.kind == FileKind::Directory {
+                reply.error(libc::EISDIR);
+                return;
+            }
+            let writable = match fh {
+                Some(fh) => self.check_file_handle_write(fh),
+                None => check_access(attrs.uid, attrs.gid, attrs.mode, req.uid(), req.gid(), libc::W_OK),
+            };
+            if !writable {
+                reply.error(libc::EACCES);
+                return;
+            }
+            if self.streamed.contains(&inode) {
+                if self.is_oversize(inode, attrs.size) {
+                    reply.error(libc::EFBIG);
+                    return;
+                }
+                // Streamed by --stream-reads, it is downloaded below and read from the cache from now on
+                self.streamed.remove(&inode);
+            }
+            if self.options.metadata_only {
+                reply.error(libc::EACCES);
+                return;
+            }
+            if let Err(error_code) = self.check_append_only(inode, size) {
+                reply.error(error_code);
+                return;
+            }
+            // A file read block by block gets its remaining blocks, they are kept or uploaded
+            if let Some(partial_size) = self.partial.get(&inode).map(|partial| partial.size) {
+                if let Err(error_code) = self.fetch_blocks(inode, 0, partial_size) {
+                    reply.error(error_code);
+                    return;
+                }
+            }
+            // The start of the file is kept, so contents that were never downloaded are fetched first
+            if size > 0 && self.cache.cached_len(inode).is_none() {
+                let filename = match self.get_filename_from_inode(inode) {
+                    Ok(filename) => filename,
+                    Err(error_code) => {
+                        reply.error(error_code);
+                        return;
+                    }
+                };
+                let rt = Runtime::new().unwrap();
+                let fetched = if attrs.segments.is_empty() {
+                    rt.block_on(self.stat_object(&filename)).and_then(|(metadata, source)| {
+                        self.fetch_content(&rt, source, &filename, inode, metadata.content_md5())
+                    })
+                } else {
+                    self.fetch_segments(&rt, &filename, &attrs)
+                };
+                match fetched {
+                    // Never uploaded, there is nothing to keep
+                    Ok(_) | Err(WorkerError::NotFound(_)) => {}
+                    Err(e) => {
+                        error!("Unable to download {}: {}", filename, e);
+                        reply.error(e.errno());
+                        return;
+                    }
+                }
+            }
+            if let Err(e) = self.unshare_content(inode).and_then(|_| self.cache.truncate(inode, size)) {
+                error!("Unable to truncate inode {}: {}", inode, e);
+                reply.error(libc::EIO);
+                return;
+            }
+            attrs.size = size;
+            // The contents no longer match the backend object
+            attrs.md5 = "".to_string();
+            attrs.dirty = true;
+            self.pending_uploads.lock().unwrap().insert(inode, size);
+            attrs.last_modified = time_now();
+            attrs.last_metadata_changed = time_now();
+            clear_suid_sgid(&mut attrs);
+        }
+
+        for time in [atime, mtime].iter().flatten() {
+            // Anyone who can write may set the current time, only the owner may pick one
+            if req.uid() != 0 && req.uid() != attrs.uid {
+                if !matches!(time, TimeOrNow::Now) {
+                    reply.error(libc::EPERM);
+                    return;
+                }
+                if !check_access(attrs.uid, attrs.gid, attrs.mode, req.uid(), req.gid(), libc::W_OK) {
+                    reply.error(libc::EACCES);
+                    return;
+                }
+            }
+        }
+        if let Some(atime) = atime {
+            attrs.last_accessed = time_from_time_or_now(atime);
+            attrs.last_metadata_changed = time_now();
+        }
+        if let Some(mtime) = mtime {
+            attrs.last_modified = time_from_time_or_now(mtime);
+            attrs.last_metadata_changed = time_now();
+        }
+
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+        // Write handles upload the new size on flush or release, a plain truncate(2) has
+        // neither so the object is updated right away
+        let has_write_handle = matches!(fh, Some(fh) if self.check_file_handle_write(fh));
+        if size.is_some() && !has_write_handle && attrs.hardlinks > 0 {
+            if let Err(error_code) = self.upload_content(&mut attrs) {
+                reply.error(error_code);
+                return;
+            }
+        }
+        reply.attr(&Duration::new(0, 0), &self.file_attr(attrs));
+    }
+
+    // Open a file. Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are available in flags. 
+    // Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and use this in other all other file operations (read, write, flush, release, fsync).
+    fn open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        debug!("open() called for {:?}", inode);
+        self.invalidate_if_requested();
+        self.upload_queued_files();
+        let (access_mask, read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => {
+                // Behavior is undefined, but most filesystems return EACCES
+                if flags & libc::O_TRUNC != 0 {
+                    reply.error(libc::EACCES);
+                    return;
+                }
+                if flags & FMODE_EXEC != 0 {
+                    // Open is from internal exec syscall
+                    (libc::X_OK, true, false)
+                } else {
+                    (libc::R_OK, true, false)
+                }
+            }
+            libc::O_WRONLY => (libc::W_OK, false, true),
+            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+            // Exactly one access mode flag must be specified
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        if write {
+            if let Err(error_code) = self.check_writable() {
+                reply.error(error_code);
+                return;
+            }
+        }
+
+        if inode & META_INODE_BIT != 0 {
+            // Metadata files are generated on read and never uploaded, so they can't be written
+            if write {
+                reply.error(libc::EROFS);
+            } else {
+                reply.opened(self.allocate_next_file_handle(read, write), FOPEN_DIRECT_IO);
+            }
+            return;
+        }
+
+        // Only attributes and listings are served, file contents are never downloaded
+        if self.options.metadata_only {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        // A write handle needs the whole file, the blocks a read handle didn't get to are fetched first
+        if write && self.partial.contains_key(&inode) {
+            let size = self.partial[&inode].size;
+            if let Err(error_code) = self.fetch_blocks(inode, 0, size) {
+                reply.error(error_code);
+                return;
+            }
+        }
+
+        match self.get_inode(inode) {
+            Ok(mut attr) => {
+                // check whether the file is newest version, if not, write the newest version to local cache. initial md5 is set to empty string, so when open the file for the first time, it will load the file from the cloud.
+                // let rt = Runtime::new().unwrap();
+                let rt = tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(4) // Specify the number of worker threads
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                let filename = match self.get_filename_from_inode(inode) {
+                    Ok(filename) => filename,
+                    Err(error_code) => {
+                        reply.error(error_code);
+                        return;
+                    }
+                };
+                // Another handle is already reading the file block by block. Blocks left from a
+                // handle that didn't read them all aren't taken for the object.
+                let reading_blocks = self.partial.contains_key(&inode);
+                let cached = self.cache.cached_len(inode).is_some() && (reading_blocks || attr.md5 != PARTIAL_MARKER);
+                // Read handles can ask for the cached bytes as they are, either for the whole
+                // mount or per open with O_NOATIME. Write handles always revalidate.
+                let serve_cached = !write && (self.options.serve_cached || flags & libc::O_NOATIME != 0);
+                // Segments never change once uploaded, so a file stored as segments is only
+                // downloaded when it isn't cached and never revalidated
+                let segmented = self.options.append_segments && !attr.segments.is_empty();
+                if segmented && !cached {
+                    if let Err(e) = self.fetch_segments(&rt, &filename, &attr) {
+                        error!("Unable to download the segments of {}: {}", filename, e);
+                        reply.error(e.errno());
+                        return;
+                    }
+                    attr.md5 = segments_marker(attr.segments.len());
+                    attr.size = attr.segments.iter().sum();
+                    if let Err(error_code) = self.write_inode(&attr) {
+                        reply.error(error_code);
+                        return;
+                    }
+                }
+                let stats = if reading_blocks || segmented || (cached && (serve_cached || self.is_immutable(&filename))) {
+                    // Objects matching an immutable glob never change, so once cached they
+                    // are served without asking the backend
+                    None
+                } else {
+                    Some(rt.block_on(self.stat_object(&filename)))
+                };
+                let mut streamed = false;
+                match stats {
+                    None => debug!("open() serving {} from cache without revalidating", filename),
+                    Some(Ok((metadata, source))) => {
+                        // The object changed when its version no longer matches the cached one.
+                        // Local changes that weren't uploaded yet are kept either way.
+                        if !cached || (!attr.dirty && object_version(&metadata) != attr.md5) {
+                            load_object_headers(&mut attr.xattrs, &metadata);
+                            // An object that can't fit in the cache is never downloaded whole
+                            let oversize = self.is_oversize(inode, metadata.content_length());
+                            let fetches_on_read = self.fetches_on_read(write, source, metadata.content_length());
+                            if oversize || (self.options.stream_reads && fetches_on_read) {
+                                if oversize && (write || self.options.oversize_policy == OversizePolicy::Error) {
+                                    reply.error(libc::EFBIG);
+                                    return;
+                                }
+                                debug!("open() streaming {} from the bucket", filename);
+                                if let Err(e) = self.cache.evict(inode) {
+                                    error!("Unable to evict inode {}: {}", inode, e);
+                                    reply.error(libc::EIO);
+                                    return;
+                                }
+                                self.release_dedup_object(&attr.md5);
+                                streamed = true;
+                                attr.md5 = object_version(&metadata);
+                                attr.last_metadata_changed = time_now();
+                                attr.last_modified = time_from_offsetdatatime(metadata.last_modified());
+                                attr.size = metadata.content_length();
+                                if let Err(error_code) = self.write_inode(&attr) {
+                                    reply.error(error_code);
+                                    return;
+                                }
+                            } else if self.options.readahead_blocks.is_some() && fetches_on_read {
+                                // Reads fetch the blocks they touch into an empty cache file of the object's size
+                                debug!("open() reading {} block by block", filename);
+                                let created = self
+                                    .cache
+                                    .evict(inode)
+                                    .and_then(|_| self.cache.truncate(inode, metadata.content_length()));
+                                if let Err(e) = created {
+                                    error!("Unable to create the contents of inode {}: {}", inode, e);
+                                    reply.error(libc::EIO);
+                                    return;
+                                }
+                                self.release_dedup_object(&attr.md5);
+                                attr.md5 = PARTIAL_MARKER.to_string();
+                                attr.last_metadata_changed = time_now();
+                                attr.last_modified = time_from_offsetdatatime(metadata.last_modified());
+                                attr.size = metadata.content_length();
+                                clear_suid_sgid(&mut attr);
+                                if let Err(error_code) = self.write_inode(&attr) {
+                                    reply.error(error_code);
+                                    return;
+                                }
+                                self.partial.insert(
+                                    inode,
+                                    PartialFile {
+                                        key: filename.clone(),
+                                        from_origin: !same_backend(source, self.worker.as_ref()),
+                                        size: attr.size,
+                                        md5: object_version(&metadata),
+                                        fetched: HashSet::new(),
+                                        prefetched: Arc::new(Mutex::new(HashMap::new())),
+                                        read_ends: HashMap::new(),
+                                    },
+                                );
+                                // Reads of the fetched ranges are served from the cache, a failure
+                                // only leaves them to those reads
+                                let head_and_tail = prefetch_ranges(
+                                    attr.size,
+                                    self.options.prefetch_head_bytes,
+                                    self.options.prefetch_tail_bytes,
+                                );
+                                for (offset, len) in head_and_tail {
+                                    if let Err(error_code) = self.fetch_blocks(inode, offset, len) {
+                                        warn!("Unable to prefetch {} bytes at {} of {}: {}", len, offset, filename, error_code);
+                                    }
+                                }
+                            } else {
+                                match self.fetch_content(&rt, source, &filename, inode, metadata.content_md5()) {
+                                    Ok(total_bytes_read) => {
+                                        debug!("open() downloaded {} bytes of {}", total_bytes_read, filename);
+                                        self.release_dedup_object(&attr.md5);
+                                        if self.options.origin_fill && !same_backend(source, self.worker.as_ref()) {
+                                            let filled = rt.block_on(self.put_content(&filename, inode, total_bytes_read));
+                                            if let Err(e) = filled {
+                                                warn!("Unable to fill {} from the origin bucket: {}", filename, e);
+                                            }
+                                        }
+                                        attr.md5 = object_version(&metadata);
+                                        attr.last_metadata_changed = time_now();
+                                        attr.last_modified = time_from_offsetdatatime(metadata.last_modified());
+                                        attr.size = total_bytes_read;
+                                        clear_suid_sgid(&mut attr);
+                                        if let Err(error_code) = self.write_inode(&attr) {
+                                            reply.error(error_code);
+                                            return;
+                                        }
+                                        self.enforce_cache_size(inode);
+                                    }
+                                    Err(e) => {
+                                        error!("Unable to download {}: {}", filename, e);
+                                        // Don't leave a partial download behind to be taken for the object
+                                        let _ = self.cache.evict(inode);
+                                        reply.error(e.errno());
+                                        return;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    // The object isn't in the bucket (yet), e.g. it was created through the mount and
+                    // never uploaded. A write handle creates it, starting from an empty content file.
+                    Some(Err(WorkerError::NotFound(_))) => {
+                        if !cached {
+                            if !write {
+                                reply.error(libc::ENOENT);
+                                return;
+                            }
+                            if let Err(e) = self.cache.truncate(inode, 0) {
+                                error!("Unable to create the contents of inode {}: {}", inode, e);
+                                reply.error(libc::EIO);
+                                return;
+                            }
+                            attr.size = 0;
+                            attr.dirty = true;
+                            attr.last_modified = time_now();
+                            attr.last_metadata_changed = time_now();
+                            if let Err(error_code) = self.write_inode(&attr) {
+                                reply.error(error_code);
+                                return;
+                            }
+                        }
+                    }
+                    Some(Err(e)) => {
+                        error!("Unable to stat {}: {}", filename, e);
+                        reply.error(e.errno());
+                        return;
+                    }
+                }
+                if streamed {
+                    self.streamed.insert(inode);
+                } else {
+                    self.streamed.remove(&inode);
+                }
+                if check_access(
+                    attr.uid,
+                    attr.gid,
+                    attr.mode,
+                    req.uid(),
+                    req.gid(),
+                    access_mask,
+                ) {
+                    attr.open_file_handles += 1;
+                    self.cache_lru.lock().unwrap().insert(inode, Instant::now());
+                    if let Err(error_code) = self.write_inode(&attr) {
+                        reply.error(error_code);
+                        return;
+                    }
+                    let open_flags = if self.options.direct_io { FOPEN_DIRECT_IO } else { 0 };
+                    reply.opened(self.allocate_next_file_handle(read, write), open_flags);
+                } else {
+                    reply.error(libc::EACCES);
+                }
+                return;
+            }
+            Err(error_code) => reply.error(error_code),
+        }
+    }
+
+    // Read data. Read should send exactly the number of bytes requested except on EOF or error, otherwise the rest of the data will be substituted with zeroes. 
+    // An exception to this is when the file has been opened in ‘direct_io’ mode, in which case the return value of the read system call will reflect the return value of this operation. 
+    // fh will contain the value set by the open method, or will be undefined if the open method didn’t set any value. 
+    // flags: these are the file flags, such as O_SYNC.
+    fn read(
+        &mut self,
+        _req: &Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        debug!(
+            "read() called on {:?} offset={:?} size={:?}",
+            inode, offset, size
+        );
+        assert!(offset >= 0);
+        if !self.check_file_handle_read(fh) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        if inode & META_INODE_BIT != 0 {
+            match self.meta_file_content(inode) {
+                Ok(content) => {
+                    let start = min(offset as u64, content.len() as u64) as usize;
+                    let end = min(start + size as usize, content.len());
+                    reply.data(&content[start..end]);
+                }
+                Err(error_code) => reply.error(error_code),
+            }
+            return;
+        }
+
+        // The inode size is the single source of truth, so readers observe a concurrent
+        // writer's growth as soon as the inode is updated, no matter how they opened the file
+        let file_size = match self.get_inode(inode) {
+            Ok(attrs) => attrs.size,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        // Clamp in u64 first, more than 4 GiB left past the offset must not wrap the u32
+        let read_size = min(size as u64, file_size.saturating_sub(offset as u64)) as u32;
+
+        if self.streamed.contains(&inode) {
+            let filename = match self.get_filename_from_inode(inode) {
+                Ok(filename) => filename,
+                Err(error_code) => {
+                    reply.error(error_code);
+                    return;
+                }
+            };
+            let data = if self.options.stream_reads {
+                self.stream_read(fh, &filename, offset as u64, read_size as u64)
+            } else {
+                Runtime::new().unwrap().block_on(self.read_object_range(&filename, offset as u64, read_size as u64))
+            };
+            match data {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    error!("Unable to read {}: {}", filename, e);
+                    reply.error(e.errno());
+                }
+            }
+            return;
+        }
+
+        if self.partial.contains_key(&inode) {
+            if let Err(error_code) = self.fetch_blocks(inode, offset as u64, read_size as u64) {
+                reply.error(error_code);
+                return;
+            }
+            self.prefetch_blocks(inode, fh, offset as u64, read_size as u64);
+        }
+
+        let mut buffer = vec![0; read_size as usize];
+        // Anything the cache doesn't hold yet within the inode size reads as zeros
+        match self.cache.read_range(inode, offset as u64, &mut buffer) {
+            Ok(_) => reply.data(&buffer),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => reply.error(libc::ENOENT),
+            Err(e) => {
+                error!("Unable to read inode {}: {}", inode, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        debug!("write() called with {:?} size={:?}", inode, data.len());
+        if let Err(error_code) = self.check_writable() {
+            reply.error(error_code);
+            return;
+        }
+        assert!(offset >= 0);
+        if !self.check_file_handle_write(fh) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        if let Err(error_code) = self.check_append_only(inode, offset as u64) {
+            reply.error(error_code);
+            return;
+        }
+        if let Err(error_code) = self.throttle_writes(data.len() as u64, flags) {
+            reply.error(error_code);
+            return;
+        }
+        if let Err(e) = self.unshare_content(inode) {
+            error!("Unable to copy shared contents of {}: {}", inode, e);
+            reply.error(libc::EIO);
+            return;
+        }
+        match self.cache.write_range(inode, offset as u64, data) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                reply.error(libc::EBADF);
+                return;
+            }
+            Err(e) => {
+                error!("Unable to write inode {}: {}", inode, e);
+                reply.error(libc::EIO);
+                return;
+            }
+            Ok(()) => {}
+        }
+
+        // Re-read the inode after the data is on disk so a size grown by another handle
+        // in the meantime is never shrunk back
+        let mut attrs = match self.get_inode(inode) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        let previous_mode = attrs.mode;
+        // The first write after a download marks the inode dirty on disk right away, so a
+        // crash can't leave changed contents that look clean and never get uploaded
+        let was_clean = !attrs.dirty;
+        attrs.last_metadata_changed = time_now();
+        attrs.last_modified = time_now();
+        // The contents no longer match the backend object
+        attrs.md5 = "".to_string();
+        attrs.dirty = true;
+        let end = offset as u64 + data.len() as u64;
+        if end > attrs.size {
+            attrs.size = end;
+        }
+        self.pending_uploads.lock().unwrap().insert(inode, attrs.size);
+        clear_suid_sgid(&mut attrs);
+        // A synchronous write is only done once its data and the size it grew to are on stable
+        // storage, so its inode update can't wait
+        let sync = flags & (libc::O_SYNC | libc::O_DSYNC) != 0;
+        if was_clean || attrs.mode != previous_mode || sync {
+            if let Err(error_code) = self.write_inode(&attrs) {
+                reply.error(error_code);
+                return;
+            }
+        } else {
+            if let Err(error_code) = self.defer_inode_write(attrs.clone()) {
+                reply.error(error_code);
+                return;
+            }
+        }
+        // It is acknowledged once it is in the bucket, even with --writeback-delay. A file
+        // with no names left has no object to write to.
+        if sync && attrs.hardlinks > 0 {
+            if let Err(error_code) = self.upload_content(&mut attrs) {
+                reply.error(error_code);
+                return;
+            }
+        }
+
+        reply.written(data.len() as u32);
+    }
+
+    // Upload the contents written through this handle so far, keeping it open, as
+    // --fsync-on-close asks. By default flushing a file that isn't dirty is a no-op.
+    fn flush(&mut self, _req: &Request<'_>, inode: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush() called with {:?} {:?}", inode, fh);
+        if inode & META_INODE_BIT != 0 || !self.check_file_handle_write(fh) {
+            reply.ok();
+            return;
+        }
+        let mut attrs = match self.get_inode(inode) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        // A queued upload is left to the release that follows
+        let action = self.options.fsync_on_close.on_close(attrs.dirty, self.options.writeback_delay.is_some());
+        if action != CloseUpload::Now || attrs.hardlinks == 0 {
+            reply.ok();
+            return;
+        }
+        match self.upload_content(&mut attrs) {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        fh: u64,
+        _flags: i32,
+        // No lock operations are implemented, so the kernel keeps POSIX and flock locks
+        // itself and drops them when the file is closed. There is nothing to release here.
+        _lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!("release() called with {:?} {:?} flush={:?}", inode, fh, flush);
+        if inode & META_INODE_BIT != 0 {
+            reply.ok();
+            return;
+        }
+        let mut attrs = match self.get_inode(inode) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        attrs.open_file_handles = attrs.open_file_handles.saturating_sub(1);
+        self.readers.remove(&fh);
+        if let Some(partial) = self.partial.get_mut(&inode) {
+            partial.read_ends.remove(&fh);
+        }
+        // Nobody reads the missing blocks anymore, the next open downloads the file again
+        if attrs.open_file_handles == 0 && self.partial.remove(&inode).is_some() {
+            if let Err(e) = self.cache.evict(inode) {
+                warn!("Unable to evict inode {}: {}", inode, e);
+            }
+        }
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        // --fsync-on-close picks the files uploaded, unlinked ones have no key left to upload to.
+        // The kernel asks for a flush along with the release when it skipped the flush call, which
+        // then applies to any handle. Otherwise the flush already uploaded what `always` asks for.
+        // With --writeback-delay the upload is queued, the cached contents stay authoritative
+        // until it is made.
+        let mut result = Ok(());
+        let upload = self.check_file_handle_write(fh) || flush;
+        let flushed = !flush && !attrs.dirty;
+        if upload && attrs.hardlinks > 0 && !flushed {
+            match self.options.fsync_on_close.on_close(attrs.dirty, self.options.writeback_delay.is_some()) {
+                CloseUpload::Now => result = self.upload_content(&mut attrs),
+                CloseUpload::Queue => self.queue_upload(inode),
+                CloseUpload::Skip => {}
+            }
+        }
+        self.gc_inode(&attrs);
+        self.upload_queued_files();
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error_code) => reply.error(error_code),
+        }
+    }
+
+    // Copy a range between two cached files without passing the bytes through FUSE
+    fn copy_file_range(
+        &mut self,
+        _req: &Request,
+        src_inode: u64,
+        src_fh: u64,
+        src_offset: i64,
+        dest_inode: u64,
+        dest_fh: u64,
+        dest_offset: i64,
+        size: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            "copy_file_range() called with src={:?} dest={:?} size={:?}",
+            src_inode, dest_inode, size
+        );
+        if let Err(error_code) = self.check_writable() {
+            reply.error(error_code);
+            return;
+        }
+        assert!(src_offset >= 0 && dest_offset >= 0);
+        if !self.check_file_handle_read(src_fh) || !self.check_file_handle_write(dest_fh) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        if let Err(error_code) = self.check_append_only(dest_inode, dest_offset as u64) {
+            reply.error(error_code);
+            return;
+        }
+
+        let src_size = match self.get_inode(src_inode) {
+            Ok(attrs) => attrs.size,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        // The reply can only report a u32 worth of bytes, the kernel issues more calls for the rest
+        let len = min(size, src_size.saturating_sub(src_offset as u64)).min(u32::MAX as u64);
+        if let Err(error_code) = self.fetch_blocks(src_inode, src_offset as u64, len) {
+            reply.error(error_code);
+            return;
+        }
+        if let Err(e) = self.unshare_content(dest_inode) {
+            error!("Unable to copy shared contents of {}: {}", dest_inode, e);
+            reply.error(libc::EIO);
+            return;
+        }
+        // Caches without files copy through a buffer instead
+        let result = match (self.cache.file_path(src_inode), self.cache.file_path(dest_inode)) {
+            (Some(src_path), Some(dest_path)) => {
+                copy_between_files(&src_path, src_offset, &dest_path, dest_offset, len)
+            }
+            _ => self.copy_through_cache(src_inode, src_offset as u64, dest_inode, dest_offset as u64, len),
+        };
+        let copied = match result {
+            Ok(copied) => copied,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                reply.error(libc::EBADF);
+                return;
+            }
+            Err(e) => {
+                error!("Unable to copy inode {} to {}: {}", src_inode, dest_inode, e);
+                reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+                return;
+            }
+        };
+
+        let mut attrs = match self.get_inode(dest_inode) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        attrs.last_metadata_changed = time_now();
+        attrs.last_modified = time_now();
+        attrs.md5 = "".to_string();
+        attrs.dirty = true;
+        if dest_offset as u64 + copied > attrs.size {
+            attrs.size = dest_offset as u64 + copied;
+        }
+        self.pending_uploads.lock().unwrap().insert(dest_inode, attrs.size);
+        clear_suid_sgid(&mut attrs);
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        reply.written(copied as u32);
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mut mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!("create() called with {:?} {:?}", parent, name);
+        if let Err(error_code) = self.check_namespace_writable() {
+            reply.error(error_code);
+            return;
+        }
+        if self.lookup_name(parent, name).is_ok() {
+            reply.error(libc::EEXIST);
+            return;
+        }
+
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            // Exactly one access mode flag must be specified
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let mut parent_attrs = match self.get_inode(parent) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        if !check_access(
+            parent_attrs.uid,
+            parent_attrs.gid,
+            parent_attrs.mode,
+            req.uid(),
+            req.gid(),
+            libc::W_OK,
+        ) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        parent_attrs.last_modified = time_now();
+        parent_attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&parent_attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        if req.uid() != 0 {
+            mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+        }
+
+        let inode = match self.allocate_next_inode() {
+            Ok(inode) => inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        let attrs = InodeAttributes {
+            inode,
+            open_file_handles: 1,
+            size: 0,
+            last_accessed: time_now(),
+            last_modified: time_now(),
+            last_metadata_changed: time_now(),
+            created: time_now(),
+            kind: as_file_kind(mode),
+            mode: self.creation_mode(mode),
+            hardlinks: 1,
+            uid: req.uid(),
+            gid: creation_gid(&parent_attrs, req.gid(), self.options.bsd_groups),
+            // a dummy md5, will update after writting content to it
+            md5: "".to_string(),
+            // a new directory has nothing in the backend to list
+            expanded: true,
+            xattrs: BTreeMap::new(),
+            segments: Vec::new(),
+            // The new file reaches the bucket with its first upload
+            dirty: as_file_kind(mode) == FileKind::File,
+        };
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+        if as_file_kind(mode) == FileKind::File {
+            if let Err(e) = self.cache.truncate(inode, 0) {
+                error!("Unable to create the contents of inode {}: {}", inode, e);
+                reply.error(libc::EIO);
+                return;
+            }
+            self.cache_lru.lock().unwrap().insert(inode, Instant::now());
+        }
+
+        if as_file_kind(mode) == FileKind::Directory {
+            let mut entries = BTreeMap::new();
+            entries.insert(b".".to_vec(), (inode, FileKind::Directory));
+            entries.insert(b"..".to_vec(), (parent, FileKind::Directory));
+            if let Err(error_code) = self.write_directory_content(inode, entries) {
+                reply.error(error_code);
+                return;
+            }
+        }
+
+        let mut entries = match self.get_directory_content(parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        entries.insert(name.as_bytes().to_vec(), (inode, attrs.kind));
+        if let Err(error_code) = self.write_directory_content(parent, entries) {
+            reply.error(error_code);
+            return;
+        }
+        self.add_name(inode, parent, name.as_bytes());
+
+        reply.created(
+            &Duration::new(0, 0),
+            &self.file_attr(attrs),
+            0,
+            self.allocate_next_file_handle(read, write),
+            0,
+        );
+    }
+
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mut mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("mkdir() called with {:?} {:?} {:o}", parent, name, mode);
+        if let Err(error_code) = self.check_namespace_writable() {
+            reply.error(error_code);
+            return;
+        }
+        if self.lookup_name(parent, name).is_ok() {
+            reply.error(libc::EEXIST);
+            return;
+        }
+
+        let mut parent_attrs = match self.get_inode(parent) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        if !check_access(
+            parent_attrs.uid,
+            parent_attrs.gid,
+            parent_attrs.mode,
+            req.uid(),
+            req.gid(),
+            libc::W_OK,
+        ) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        // The marker goes first, a directory the bucket doesn't know about would vanish on remount
+        let key = match self.directory_key(parent) {
+            Ok(prefix) => format!("{}{}/", prefix, name.to_string_lossy()),
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if let Err(e) = self.create_dir_marker(&key) {
+            error!("Unable to create directory marker {}: {}", key, e);
+            reply.error(e.errno());
+            return;
+        }
+
+        parent_attrs.last_modified = time_now();
+        parent_attrs.last_metadata_changed = time_now();
+        // The new directory's ".." links to the parent
+        parent_attrs.hardlinks += 1;
+        if let Err(error_code) = self.write_inode(&parent_attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        if req.uid() != 0 {
+            mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+        }
+
+        let inode = match self.allocate_next_inode() {
+            Ok(inode) => inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        let attrs = InodeAttributes {
+            inode,
+            open_file_handles: 0,
+            size: BLOCK_SIZE,
+            last_accessed: time_now(),
+            last_modified: time_now(),
+            last_metadata_changed: time_now(),
+            created: time_now(),
+            kind: FileKind::Directory,
+            mode: self.creation_mode(mode),
+            hardlinks: 2,
+            uid: req.uid(),
+            gid: creation_gid(&parent_attrs, req.gid(), self.options.bsd_groups),
+            md5: "".to_string(),
+            // a new directory has nothing in the backend to list
+            expanded: true,
+            xattrs: BTreeMap::new(),
+            segments: Vec::new(),
+            dirty: false,
+        };
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        let mut entries = BTreeMap::new();
+        entries.insert(b".".to_vec(), (inode, FileKind::Directory));
+        entries.insert(b"..".to_vec(), (parent, FileKind::Directory));
+        if let Err(error_code) = self.write_directory_content(inode, entries) {
+            reply.error(error_code);
+            return;
+        }
+
+        let mut entries = match self.get_directory_content(parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        entries.insert(name.as_bytes().to_vec(), (inode, FileKind::Directory));
+        if let Err(error_code) = self.write_directory_content(parent, entries) {
+            reply.error(error_code);
+            return;
+        }
+
+        reply.entry(&Duration::new(0, 0), &self.file_attr(attrs), 0);
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!("symlink() called with {:?} {:?} {:?}", parent, link_name, target);
+        if let Err(error_code) = self.check_namespace_writable() {
+            reply.error(error_code);
+            return;
+        }
+        if self.lookup_name(parent, link_name).is_ok() {
+            reply.error(libc::EEXIST);
+            return;
+        }
+
+        let mut parent_attrs = match self.get_inode(parent) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        if !check_access(
+            parent_attrs.uid,
+            parent_attrs.gid,
+            parent_attrs.mode,
+            req.uid(),
+            req.gid(),
+            libc::W_OK,
+        ) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        // Mirrored to the bucket first, so a remount rebuilds the link
+        let target = target.as_os_str().as_bytes();
+        let key = match self.directory_key(parent) {
+            Ok(prefix) => format!("{}{}", prefix, link_name.to_string_lossy()),
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if let Err(e) = Runtime::new().unwrap().block_on(self.worker.put_symlink(&key, target)) {
+            error!("Unable to upload symbolic link {}: {}", key, e);
+            reply.error(e.errno());
+            return;
+        }
+
+        parent_attrs.last_modified = time_now();
+        parent_attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&parent_attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        let inode = match self.allocate_next_inode() {
+            Ok(inode) => inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        let attrs = InodeAttributes {
+            inode,
+            open_file_handles: 0,
+            size: target.len() as u64,
+            last_accessed: time_now(),
+            last_modified: time_now(),
+            last_metadata_changed: time_now(),
+            created: time_now(),
+            kind: FileKind::Symlink,
+            // Permissions of a symbolic link are never checked
+            mode: 0o777,
+            hardlinks: 1,
+            uid: req.uid(),
+            gid: creation_gid(&parent_attrs, req.gid(), self.options.bsd_groups),
+            md5: "".to_string(),
+            expanded: true,
+            xattrs: BTreeMap::new(),
+            segments: Vec::new(),
+            dirty: false,
+        };
+        if let Err(e) = fs::write(self.symlink_path(inode), target) {
+            error!("Unable to store symbolic link {}: {}", inode, e);
+            reply.error(libc::EIO);
+            return;
+        }
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        let mut entries = match self.get_directory_content(parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        entries.insert(link_name.as_bytes().to_vec(), (inode, FileKind::Symlink));
+        if let Err(error_code) = self.write_directory_content(parent, entries) {
+            reply.error(error_code);
+            return;
+        }
+        self.add_name(inode, parent, link_name.as_bytes());
+
+        reply.entry(&Duration::new(0, 0), &self.file_attr(attrs), 0);
+    }
+
+    // Object stores can't link two keys to one object. The new name gets its own copy of the
+    // object (server-side where the service supports it), so other clients of the bucket see a
+    // regular file under both names and a remount discovers them as two independent files.
+    // Within this mount both names share the inode and its cached contents, and changes are
+    // uploaded to the key get_filename_from_inode resolves for it.
+    fn link(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        new_parent: u64,
+        new_name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!("link() called for {} {:?} {:?}", inode, new_parent, new_name);
+        if let Err(error_code) = self.check_namespace_writable() {
+            reply.error(error_code);
+            return;
+        }
+        let mut attrs = match self.get_inode(inode) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        if attrs.kind == FileKind::Directory {
+            reply.error(libc::EPERM);
+            return;
         }
-        Ok(())
-    }
-
-    // Look up a directory entry by name and get its attributes.
-    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if name.len() > MAX_NAME_LENGTH as usize {
-            reply.error(libc::ENAMETOOLONG);
+        if self.lookup_name(new_parent, new_name).is_ok() {
+            reply.error(libc::EEXIST);
             return;
         }
-        let parent_attrs = self.get_inode(parent).unwrap();
+
+        let mut parent_attrs = match self.get_inode(new_parent) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
         if !check_access(
             parent_attrs.uid,
             parent_attrs.gid,
             parent_attrs.mode,
             req.uid(),
             req.gid(),
-            libc::X_OK,
+            libc::W_OK,
         ) {
             reply.error(libc::EACCES);
             return;
         }
 
-        match self.lookup_name(parent, name) {
-            Ok(attrs) => reply.entry(&Duration::new(0, 0), &attrs.into(), 0),
-            Err(error_code) => reply.error(error_code),
+        let key = match self.directory_key(new_parent) {
+            Ok(prefix) => format!("{}{}", prefix, new_name.to_string_lossy()),
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        let rt = Runtime::new().unwrap();
+        let copied = if attrs.kind == FileKind::Symlink {
+            match fs::read(self.symlink_path(inode)) {
+                Ok(target) => rt.block_on(self.worker.put_symlink(&key, &target)),
+                Err(e) => Err(e.into()),
+            }
+        } else {
+            match self.get_filename_from_inode(inode) {
+                Ok(filename) => rt.block_on(self.worker.copy(&filename, &key)),
+                Err(error_code) => {
+                    reply.error(error_code);
+                    return;
+                }
+            }
+        };
+        match copied {
+            // Files created through the mount may not have been uploaded yet
+            Ok(()) | Err(WorkerError::NotFound(_)) => {}
+            Err(e) => {
+                error!("Unable to copy inode {} to {}: {}", inode, key, e);
+                reply.error(e.errno());
+                return;
+            }
+        }
+
+        parent_attrs.last_modified = time_now();
+        parent_attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&parent_attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        attrs.hardlinks += 1;
+        attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        let mut entries = match self.get_directory_content(new_parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        entries.insert(new_name.as_bytes().to_vec(), (inode, attrs.kind));
+        if let Err(error_code) = self.write_directory_content(new_parent, entries) {
+            reply.error(error_code);
+            return;
         }
+        self.add_name(inode, new_parent, new_name.as_bytes());
+
+        reply.entry(&Duration::new(0, 0), &self.file_attr(attrs), 0);
     }
 
-    // Get file attributes.
-    fn getattr(&mut self, _req: &Request, inode: u64, reply: ReplyAttr) {
+    fn readlink(&mut self, _req: &Request, inode: u64, reply: ReplyData) {
+        debug!("readlink() called with {:?}", inode);
         match self.get_inode(inode) {
-            Ok(attrs) => reply.attr(&Duration::new(0, 0), &attrs.into()),
-            Err(error_code) => reply.error(error_code),
+            Ok(attrs) if attrs.kind == FileKind::Symlink => {}
+            Ok(_) => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        }
+        if let Err(error_code) = self.check_symlink_depth(inode) {
+            reply.error(error_code);
+            return;
+        }
+        match fs::read(self.symlink_path(inode)) {
+            Ok(target) => reply.data(&target),
+            Err(e) => {
+                error!("Unable to read symbolic link {}: {}", inode, e);
+                reply.error(libc::EIO);
+            }
         }
     }
 
-    // Open a file. Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are available in flags. 
-    // Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and use this in other all other file operations (read, write, flush, release, fsync).
-    fn open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
-        debug!("open() called for {:?}", inode);
+    // Open a directory. Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and use this in other all other directory stream operations (readdir, releasedir, fsyncdir). 
+    fn opendir(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        debug!("opendir() called on {:?}", inode);
         let (access_mask, read, write) = match flags & libc::O_ACCMODE {
             libc::O_RDONLY => {
                 // Behavior is undefined, but most filesystems return EACCES
@@ -483,12 +3640,7 @@ impl Filesystem for S3FS {
                     reply.error(libc::EACCES);
                     return;
                 }
-                if flags & FMODE_EXEC != 0 {
-                    // Open is from internal exec syscall
-                    (libc::X_OK, true, false)
-                } else {
-                    (libc::R_OK, true, false)
-                }
+                (libc::R_OK, true, false)
             }
             libc::O_WRONLY => (libc::W_OK, false, true),
             libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
@@ -501,31 +3653,6 @@ impl Filesystem for S3FS {
 
         match self.get_inode(inode) {
             Ok(mut attr) => {
-                // check whether the file is newest version, if not, write the newest version to local cache. initial md5 is set to empty string, so when open the file for the first time, it will load the file from the cloud.
-                // let rt = Runtime::new().unwrap();
-                let rt = tokio::runtime::Builder::new_multi_thread()
-                    .worker_threads(4) // Specify the number of worker threads
-                    .enable_all()
-                    .build()
-                    .unwrap();
-                let filename = self.get_filename_from_inode(inode);
-                let metadata = rt.block_on(self.worker.get_stats(&filename)).unwrap();
-                // if metadata.content_md5().unwrap().to_string() != attr.md5 {
-                if time_from_offsetdatatime(metadata.last_modified()) != attr.last_modified {
-                    let path = self.content_path(inode);
-                    match rt.block_on(self.worker.get_data(filename.as_str(), path.to_str().unwrap())) {
-                        Ok(total_bytes_read) => {
-                            println!("Downloaded {} bytes", total_bytes_read);
-                            attr.md5 = metadata.content_md5().unwrap().to_string();
-                            attr.last_metadata_changed = time_now();
-                            attr.last_modified = time_from_offsetdatatime(metadata.last_modified());
-                            attr.size = total_bytes_read;
-                            clear_suid_sgid(&mut attr);
-                            self.write_inode(&attr);
-                        }
-                        Err(e) => println!("Error: {}", e),
-                    }
-                }
                 if check_access(
                     attr.uid,
                     attr.gid,
@@ -535,8 +3662,11 @@ impl Filesystem for S3FS {
                     access_mask,
                 ) {
                     attr.open_file_handles += 1;
-                    self.write_inode(&attr);
-                    let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
+                    if let Err(error_code) = self.write_inode(&attr) {
+                        reply.error(error_code);
+                        return;
+                    }
+                    let open_flags = if self.options.direct_io { FOPEN_DIRECT_IO } else { 0 };
                     reply.opened(self.allocate_next_file_handle(read, write), open_flags);
                 } else {
                     reply.error(libc::EACCES);
@@ -547,112 +3677,320 @@ impl Filesystem for S3FS {
         }
     }
 
-    // Read data. Read should send exactly the number of bytes requested except on EOF or error, otherwise the rest of the data will be substituted with zeroes. 
-    // An exception to this is when the file has been opened in ‘direct_io’ mode, in which case the return value of the read system call will reflect the return value of this operation. 
-    // fh will contain the value set by the open method, or will be undefined if the open method didn’t set any value. 
-    // flags: these are the file flags, such as O_SYNC.
-    fn read(
+    // Read directory. Send a buffer filled using buffer.fill(), with size not exceeding the requested size. 
+    // Send an empty buffer on end of stream. fh will contain the value set by the opendir method, or will be undefined if the opendir method didn’t set any value.
+    fn readdir(
         &mut self,
         _req: &Request,
         inode: u64,
-        fh: u64,
+        _fh: u64,
         offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
+        mut reply: ReplyDirectory,
     ) {
-        debug!(
-            "read() called on {:?} offset={:?} size={:?}",
-            inode, offset, size
-        );
+        debug!("readdir() called with {:?}", inode);
         assert!(offset >= 0);
-        if !self.check_file_handle_read(fh) {
+        if let Err(error_code) = self.ensure_expanded(inode) {
+            reply.error(error_code);
+            return;
+        }
+        // Only the metadata file view needs the whole descriptor to spot name collisions,
+        // otherwise entries are decoded one by one until the reply buffer is full
+        let listing: DirectoryListing = if self.options.show_meta_files {
+            let entries = match self.get_directory_content(inode) {
+                Ok(entries) => entries,
+                Err(error_code) => {
+                    reply.error(error_code);
+                    return;
+                }
+            };
+            let mut listing = Vec::with_capacity(entries.len());
+            for (name, (inode, file_type)) in entries.iter() {
+                listing.push((name.clone(), *inode, *file_type));
+                // Don't shadow a real object that happens to carry the suffix
+                let meta_name = [name.as_slice(), META_FILE_SUFFIX].concat();
+                if *file_type == FileKind::File && !entries.contains_key(&meta_name) {
+                    listing.push((meta_name, *inode | META_INODE_BIT, FileKind::File));
+                }
+            }
+            // Stable, so everything else stays in name order
+            listing.sort_by_key(|(name, _, _)| name != b"." && name != b"..");
+            Box::new(listing.into_iter())
+        } else {
+            match self.name_ordered_listing(inode) {
+                Ok(listing) => listing,
+                Err(error_code) => {
+                    reply.error(error_code);
+                    return;
+                }
+            }
+        };
+
+        // Offsets index into the sorted listing, so a directory that doesn't change between
+        // calls pages consistently. Entries added while paging can shift others across pages.
+        let listing = if self.options.readdir_order == ReaddirOrder::Name {
+            listing
+        } else {
+            self.sort_listing(listing)
+        };
+
+        for (index, entry) in listing.skip(offset as usize).enumerate() {
+            let (name, inode, file_type) = entry;
+
+            let buffer_full: bool = reply.add(
+                inode,
+                offset + index as i64 + 1,
+                file_type.into(),
+                OsStr::from_bytes(&name),
+            );
+
+            if buffer_full {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink() called with {:?} {:?}", parent, name);
+        if let Err(error_code) = self.check_namespace_writable() {
+            reply.error(error_code);
+            return;
+        }
+        let mut attrs = match self.lookup_name(parent, name) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        // Uploaded segments are never deleted or overwritten
+        if !attrs.segments.is_empty() {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        let mut parent_attrs = match self.get_inode(parent) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        if !check_access(
+            parent_attrs.uid,
+            parent_attrs.gid,
+            parent_attrs.mode,
+            req.uid(),
+            req.gid(),
+            libc::W_OK,
+        ) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        let uid = req.uid();
+        // "Sticky bit" handling
+        if parent_attrs.mode & libc::S_ISVTX as u16 != 0
+            && uid != 0
+            && uid != parent_attrs.uid
+            && uid != attrs.uid
+        {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        parent_attrs.last_metadata_changed = time_now();
+        parent_attrs.last_modified = time_now();
+        if let Err(error_code) = self.write_inode(&parent_attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        attrs.hardlinks -= 1;
+        attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+        self.gc_inode(&attrs);
+
+        let mut entries = match self.get_directory_content(parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        entries.remove(name.as_bytes());
+        if let Err(error_code) = self.write_directory_content(parent, entries) {
+            reply.error(error_code);
+            return;
+        }
+        self.remove_name(attrs.inode, parent, name.as_bytes());
+
+        reply.ok();
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir() called with {:?} {:?}", parent, name);
+        if let Err(error_code) = self.check_namespace_writable() {
+            reply.error(error_code);
+            return;
+        }
+        let mut attrs = match self.lookup_name(parent, name) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        let mut parent_attrs = match self.get_inode(parent) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+
+        if attrs.kind != FileKind::Directory {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+
+        // A directory discovery hasn't listed yet may still hold objects in the bucket
+        if let Err(error_code) = self.ensure_expanded(attrs.inode) {
+            reply.error(error_code);
+            return;
+        }
+        match self.get_directory_content(attrs.inode) {
+            // Only "." and ".." left
+            Ok(entries) if entries.len() > 2 => {
+                reply.error(libc::ENOTEMPTY);
+                return;
+            }
+            Ok(_) => {}
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        }
+
+        if !check_access(
+            parent_attrs.uid,
+            parent_attrs.gid,
+            parent_attrs.mode,
+            req.uid(),
+            req.gid(),
+            libc::W_OK,
+        ) {
             reply.error(libc::EACCES);
             return;
         }
 
-        let path = self.content_path(inode);
-        if let Ok(file) = File::open(&path) {
-            let file_size = file.metadata().unwrap().len();
-            // Could underflow if file length is less than local_start
-            let read_size = min(size, file_size.saturating_sub(offset as u64) as u32);
+        let uid = req.uid();
+        // "Sticky bit" handling
+        if parent_attrs.mode & libc::S_ISVTX as u16 != 0
+            && uid != 0
+            && uid != parent_attrs.uid
+            && uid != attrs.uid
+        {
+            reply.error(libc::EACCES);
+            return;
+        }
 
-            let mut buffer = vec![0; read_size as usize];
-            file.read_exact_at(&mut buffer, offset as u64).unwrap();
-            reply.data(&buffer);
-        } else {
-            reply.error(libc::ENOENT);
+        let key = match self.directory_key(attrs.inode) {
+            Ok(key) => key,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        // Directories that only exist as a key prefix have no marker to delete. Until the
+        // first mkdir finds out markers are refused, --keep-marker objects are listed like any
+        // file, so a directory holding one isn't empty.
+        let marker = if self.keep_markers { format!("{}{}", key, self.options.keep_marker) } else { key };
+        match Runtime::new().unwrap().block_on(self.worker.delete(&marker)) {
+            Ok(()) | Err(WorkerError::NotFound(_)) => {}
+            Err(e) => {
+                error!("Unable to delete directory marker {}: {}", marker, e);
+                reply.error(e.errno());
+                return;
+            }
         }
-    }
 
-    fn write(
-        &mut self,
-        _req: &Request,
-        inode: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        #[allow(unused_variables)] flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        debug!("write() called with {:?} size={:?}", inode, data.len());
-        assert!(offset >= 0);
-        if !self.check_file_handle_write(fh) {
-            reply.error(libc::EACCES);
+        parent_attrs.last_metadata_changed = time_now();
+        parent_attrs.last_modified = time_now();
+        // Drop the link the removed directory's ".." held
+        parent_attrs.hardlinks = parent_attrs.hardlinks.saturating_sub(1);
+        if let Err(error_code) = self.write_inode(&parent_attrs) {
+            reply.error(error_code);
             return;
         }
 
-        let path = self.content_path(inode);
-        if let Ok(mut file) = OpenOptions::new().write(true).open(&path) {
-            file.seek(SeekFrom::Start(offset as u64)).unwrap();
-            file.write_all(data).unwrap();
+        attrs.hardlinks = 0;
+        attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+        self.gc_inode(&attrs);
 
-            let mut attrs = self.get_inode(inode).unwrap();
-            attrs.last_metadata_changed = time_now();
-            attrs.last_modified = time_now();
-            if data.len() + offset as usize > attrs.size as usize {
-                attrs.size = (data.len() + offset as usize) as u64;
+        let mut entries = match self.get_directory_content(parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
             }
-            clear_suid_sgid(&mut attrs);
-            self.write_inode(&attrs);
-
-            reply.written(data.len() as u32);
-        } else {
-            reply.error(libc::EBADF);
+        };
+        entries.remove(name.as_bytes());
+        if let Err(error_code) = self.write_directory_content(parent, entries) {
+            reply.error(error_code);
+            return;
         }
+
+        reply.ok();
     }
 
-    fn create(
+    fn rename(
         &mut self,
         req: &Request,
         parent: u64,
         name: &OsStr,
-        mut mode: u32,
-        _umask: u32,
-        flags: i32,
-        reply: ReplyCreate,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
     ) {
-        debug!("create() called with {:?} {:?}", parent, name);
-        if self.lookup_name(parent, name).is_ok() {
-            reply.error(libc::EEXIST);
+        debug!(
+            "rename() called with {:?} {:?} to {:?} {:?}",
+            parent, name, new_parent, new_name
+        );
+        if let Err(error_code) = self.check_namespace_writable() {
+            reply.error(error_code);
             return;
         }
-
-        let (read, write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => (true, false),
-            libc::O_WRONLY => (false, true),
-            libc::O_RDWR => (true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let mut attrs = match self.lookup_name(parent, name) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
                 return;
             }
         };
+        // Uploaded segments are never deleted or overwritten
+        if !attrs.segments.is_empty() {
+            reply.error(libc::EPERM);
+            return;
+        }
 
-        let mut parent_attrs = match self.get_inode(parent) {
+        let parent_attrs = match self.get_inode(parent) {
             Ok(attrs) => attrs,
             Err(error_code) => {
                 reply.error(error_code);
@@ -671,196 +4009,682 @@ impl Filesystem for S3FS {
             reply.error(libc::EACCES);
             return;
         }
-        parent_attrs.last_modified = time_now();
-        parent_attrs.last_metadata_changed = time_now();
-        self.write_inode(&parent_attrs);
 
-        if req.uid() != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+        // "Sticky bit" handling
+        if parent_attrs.mode & libc::S_ISVTX as u16 != 0
+            && req.uid() != 0
+            && req.uid() != parent_attrs.uid
+            && req.uid() != attrs.uid
+        {
+            reply.error(libc::EACCES);
+            return;
         }
 
-        let inode = self.allocate_next_inode();
-        let attrs = InodeAttributes {
-            inode,
-            open_file_handles: 1,
-            size: 0,
-            last_accessed: time_now(),
-            last_modified: time_now(),
-            last_metadata_changed: time_now(),
-            kind: as_file_kind(mode),
-            mode: self.creation_mode(mode),
-            hardlinks: 1,
-            uid: req.uid(),
-            gid: creation_gid(&parent_attrs, req.gid()),
-            // a dummy md5, will update after writting content to it
-            md5: "".to_string()
+        let new_parent_attrs = match self.get_inode(new_parent) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
         };
-        self.write_inode(&attrs);
-        File::create(self.content_path(inode)).unwrap();
 
-        if as_file_kind(mode) == FileKind::Directory {
-            let mut entries = BTreeMap::new();
-            entries.insert(b".".to_vec(), (inode, FileKind::Directory));
-            entries.insert(b"..".to_vec(), (parent, FileKind::Directory));
-            self.write_directory_content(inode, entries);
+        if !check_access(
+            new_parent_attrs.uid,
+            new_parent_attrs.gid,
+            new_parent_attrs.mode,
+            req.uid(),
+            req.gid(),
+            libc::W_OK,
+        ) {
+            reply.error(libc::EACCES);
+            return;
         }
 
-        let mut entries = self.get_directory_content(parent).unwrap();
-        entries.insert(name.as_bytes().to_vec(), (inode, attrs.kind));
-        self.write_directory_content(parent, entries);
+        // A directory can't be moved below itself
+        if attrs.kind == FileKind::Directory {
+            let mut ancestor = new_parent;
+            while ancestor != FUSE_ROOT_ID {
+                if ancestor == attrs.inode {
+                    reply.error(libc::EINVAL);
+                    return;
+                }
+                let entries = self.get_directory_content(ancestor);
+                ancestor = match entries.map(|entries| entries.get(b"..".as_ref()).copied()) {
+                    Ok(Some((grandparent, _))) => grandparent,
+                    _ => {
+                        reply.error(libc::EIO);
+                        return;
+                    }
+                };
+            }
+        }
 
-        reply.created(
-            &Duration::new(0, 0),
-            &attrs.into(),
-            0,
-            self.allocate_next_file_handle(read, write),
-            0,
-        );
-    }
+        let replaced = self.lookup_name(new_parent, new_name).ok();
+        if let Some(existing) = &replaced {
+            if existing.inode == attrs.inode {
+                reply.ok();
+                return;
+            }
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                reply.error(libc::EEXIST);
+                return;
+            }
+            if !existing.segments.is_empty() {
+                reply.error(libc::EPERM);
+                return;
+            }
+            // "Sticky bit" handling
+            if new_parent_attrs.mode & libc::S_ISVTX as u16 != 0
+                && req.uid() != 0
+                && req.uid() != new_parent_attrs.uid
+                && req.uid() != existing.uid
+            {
+                reply.error(libc::EACCES);
+                return;
+            }
+            match (attrs.kind, existing.kind) {
+                (FileKind::File | FileKind::Symlink, FileKind::Directory) => {
+                    reply.error(libc::EISDIR);
+                    return;
+                }
+                (FileKind::Directory, FileKind::File | FileKind::Symlink) => {
+                    reply.error(libc::ENOTDIR);
+                    return;
+                }
+                (FileKind::Directory, FileKind::Directory) => {
+                    if let Err(error_code) = self.ensure_expanded(existing.inode) {
+                        reply.error(error_code);
+                        return;
+                    }
+                    // Only "." and ".." left
+                    if self.get_directory_content(existing.inode).map_or(true, |entries| entries.len() > 2) {
+                        reply.error(libc::ENOTEMPTY);
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let (from_key, to_key) = match (self.directory_key(parent), self.directory_key(new_parent)) {
+            (Ok(from), Ok(to)) => (
+                format!("{}{}", from, name.to_string_lossy()),
+                format!("{}{}", to, new_name.to_string_lossy()),
+            ),
+            (Err(error_code), _) | (_, Err(error_code)) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        let rt = Runtime::new().unwrap();
+        let moved = if attrs.kind == FileKind::Directory {
+            rt.block_on(self.worker.rename_dir(&format!("{}/", from_key), &format!("{}/", to_key)))
+        } else {
+            rt.block_on(self.worker.rename_object(&from_key, &to_key))
+        };
+        match moved {
+            // Files created through the mount may not have been uploaded yet
+            Ok(()) | Err(WorkerError::NotFound(_)) => {}
+            Err(e) => {
+                error!("Unable to move {} to {}: {}", from_key, to_key, e);
+                reply.error(e.errno());
+                return;
+            }
+        }
 
+        if let Some(mut existing) = replaced {
+            existing.hardlinks = if existing.kind == FileKind::Directory { 0 } else { existing.hardlinks - 1 };
+            existing.last_metadata_changed = time_now();
+            if let Err(error_code) = self.write_inode(&existing) {
+                reply.error(error_code);
+                return;
+            }
+            self.remove_name(existing.inode, new_parent, new_name.as_bytes());
+            self.gc_inode(&existing);
+        }
 
-    // Open a directory. Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and use this in other all other directory stream operations (readdir, releasedir, fsyncdir). 
-    fn opendir(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
-        debug!("opendir() called on {:?}", inode);
-        let (access_mask, read, write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => {
-                // Behavior is undefined, but most filesystems return EACCES
-                if flags & libc::O_TRUNC != 0 {
-                    reply.error(libc::EACCES);
+        let mut entries = match self.get_directory_content(parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        entries.remove(name.as_bytes());
+        if let Err(error_code) = self.write_directory_content(parent, entries) {
+            reply.error(error_code);
+            return;
+        }
+        let mut entries = match self.get_directory_content(new_parent) {
+            Ok(entries) => entries,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        entries.insert(new_name.as_bytes().to_vec(), (attrs.inode, attrs.kind));
+        if let Err(error_code) = self.write_directory_content(new_parent, entries) {
+            reply.error(error_code);
+            return;
+        }
+        self.remove_name(attrs.inode, parent, name.as_bytes());
+        self.add_name(attrs.inode, new_parent, new_name.as_bytes());
+
+        // A moved directory's ".." link goes with it to the new parent
+        let moves_directory = attrs.kind == FileKind::Directory && parent != new_parent;
+        if moves_directory {
+            let mut entries = match self.get_directory_content(attrs.inode) {
+                Ok(entries) => entries,
+                Err(error_code) => {
+                    reply.error(error_code);
                     return;
                 }
-                (libc::R_OK, true, false)
+            };
+            entries.insert(b"..".to_vec(), (new_parent, FileKind::Directory));
+            if let Err(error_code) = self.write_directory_content(attrs.inode, entries) {
+                reply.error(error_code);
+                return;
             }
-            libc::O_WRONLY => (libc::W_OK, false, true),
-            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
+        }
+
+        // Re-read the parents, they are the same inode when renaming within a directory
+        let mut parent_attrs = match self.get_inode(parent) {
+            Ok(parent_attrs) => parent_attrs,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        parent_attrs.last_metadata_changed = time_now();
+        parent_attrs.last_modified = time_now();
+        if moves_directory {
+            parent_attrs.hardlinks = parent_attrs.hardlinks.saturating_sub(1);
+        }
+        if let Err(error_code) = self.write_inode(&parent_attrs) {
+            reply.error(error_code);
+            return;
+        }
+        let mut new_parent_attrs = match self.get_inode(new_parent) {
+            Ok(new_parent_attrs) => new_parent_attrs,
+            Err(error_code) => {
+                reply.error(error_code);
                 return;
             }
         };
+        new_parent_attrs.last_metadata_changed = time_now();
+        new_parent_attrs.last_modified = time_now();
+        if moves_directory {
+            new_parent_attrs.hardlinks += 1;
+        }
+        if let Err(error_code) = self.write_inode(&new_parent_attrs) {
+            reply.error(error_code);
+            return;
+        }
 
-        match self.get_inode(inode) {
-            Ok(mut attr) => {
-                if check_access(
-                    attr.uid,
-                    attr.gid,
-                    attr.mode,
-                    req.uid(),
-                    req.gid(),
-                    access_mask,
-                ) {
-                    attr.open_file_handles += 1;
-                    self.write_inode(&attr);
-                    let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
-                    reply.opened(self.allocate_next_file_handle(read, write), open_flags);
-                } else {
-                    reply.error(libc::EACCES);
-                }
+        attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+
+        // Files read block by block below a renamed directory moved too
+        let moved: Vec<(Inode, String)> = self
+            .partial
+            .keys()
+            .filter_map(|inode| Some((*inode, self.get_filename_from_inode(*inode).ok()?)))
+            .collect();
+        for (inode, key) in moved {
+            if let Some(partial) = self.partial.get_mut(&inode) {
+                partial.key = key;
+            }
+        }
+
+        reply.ok();
+    }
+
+    // Space is reported from the filesystem holding data_dir, since that is where written
+    // and downloaded contents go
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs() called");
+        let stats = match statvfs(Path::new(&self.data_dir)) {
+            Ok(stats) => stats,
+            Err(e) => {
+                error!("Unable to stat the filesystem of {}: {}", self.data_dir, e);
+                reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+                return;
+            }
+        };
+        let last_inode = match self.last_inode() {
+            Ok(last_inode) => last_inode,
+            Err(error_code) => {
+                reply.error(error_code);
+                return;
+            }
+        };
+        let to_blocks = |count: u64| count * stats.f_frsize as u64 / BLOCK_SIZE;
+        reply.statfs(
+            to_blocks(stats.f_blocks as u64),
+            to_blocks(stats.f_bfree as u64),
+            to_blocks(stats.f_bavail as u64),
+            last_inode,
+            stats.f_favail as u64,
+            BLOCK_SIZE as u32,
+            MAX_NAME_LENGTH,
+            BLOCK_SIZE as u32,
+        );
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr() called with {:?} {:?}", inode, name);
+        let attrs = match self.get_inode(inode) {
+            Ok(attrs) => attrs,
+            Err(error_code) => {
+                reply.error(error_code);
                 return;
             }
-            Err(error_code) => reply.error(error_code),
+        };
+        if attrs.kind == FileKind::Directory && name.as_bytes() == TREE_SIZE_XATTR {
+            let total = match self.tree_sizes.get(&inode) {
+                Some((computed, total)) if computed.elapsed() < TREE_SIZE_TTL => *total,
+                _ => match self.tree_size(inode) {
+                    Ok(total) => {
+                        self.tree_sizes.insert(inode, (Instant::now(), total));
+                        total
+                    }
+                    Err(error_code) => {
+                        reply.error(error_code);
+                        return;
+                    }
+                },
+            };
+            reply_xattr(total.to_string().as_bytes(), size, reply);
+            return;
+        }
+        if let Err(error_code) = xattr_access_check(name.as_bytes(), libc::R_OK, &attrs, req) {
+            reply.error(error_code);
+            return;
+        }
+        match attrs.xattrs.get(name.as_bytes()) {
+            Some(value) => reply_xattr(value, size, reply),
+            None => reply.error(libc::ENODATA),
         }
     }
 
-    // Read directory. Send a buffer filled using buffer.fill(), with size not exceeding the requested size. 
-    // Send an empty buffer on end of stream. fh will contain the value set by the opendir method, or will be undefined if the opendir method didn’t set any value.
-    fn readdir(
+    fn setxattr(
         &mut self,
-        _req: &Request,
+        req: &Request<'_>,
         inode: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
     ) {
-        debug!("readdir() called with {:?}", inode);
-        assert!(offset >= 0);
-        let entries = match self.get_directory_content(inode) {
-            Ok(entries) => entries,
+        debug!("setxattr() called with {:?} {:?}", inode, name);
+        if let Err(error_code) = self.check_writable() {
+            reply.error(error_code);
+            return;
+        }
+        let mut attrs = match self.get_inode(inode) {
+            Ok(attrs) => attrs,
             Err(error_code) => {
                 reply.error(error_code);
                 return;
             }
         };
-
-        for (index, entry) in entries.iter().skip(offset as usize).enumerate() {
-            let (name, (inode, file_type)) = entry;
-
-            let buffer_full: bool = reply.add(
-                *inode,
-                offset + index as i64 + 1,
-                (*file_type).into(),
-                OsStr::from_bytes(name),
-            );
-
-            if buffer_full {
-                break;
+        // The tree size is computed, it can't be set
+        if attrs.kind == FileKind::Directory && name.as_bytes() == TREE_SIZE_XATTR {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if let Err(error_code) = xattr_access_check(name.as_bytes(), libc::W_OK, &attrs, req) {
+            reply.error(error_code);
+            return;
+        }
+        let exists = attrs.xattrs.contains_key(name.as_bytes());
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            reply.error(libc::EEXIST);
+            return;
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            reply.error(libc::ENODATA);
+            return;
+        }
+        if is_object_header_xattr(name.as_bytes()) {
+            if attrs.kind != FileKind::File {
+                reply.error(libc::EPERM);
+                return;
             }
+            // Sent as an HTTP header value
+            if value.is_empty() || !value.iter().all(|byte| (b' '..=b'~').contains(byte)) {
+                reply.error(libc::EINVAL);
+                return;
+            }
+            self.mark_header_change(&mut attrs);
+        }
+        attrs.xattrs.insert(name.as_bytes().to_vec(), value.to_vec());
+        attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
         }
-
         reply.ok();
     }
 
-
-    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!("unlink() called with {:?} {:?}", parent, name);
-        let mut attrs = match self.lookup_name(parent, name) {
+    fn listxattr(&mut self, _req: &Request<'_>, inode: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr() called with {:?}", inode);
+        let attrs = match self.get_inode(inode) {
             Ok(attrs) => attrs,
             Err(error_code) => {
                 reply.error(error_code);
                 return;
             }
         };
+        // Names are null terminated, one after the other
+        let mut names = vec![];
+        if attrs.kind == FileKind::Directory {
+            names.extend_from_slice(TREE_SIZE_XATTR);
+            names.push(0);
+        }
+        for name in attrs.xattrs.keys() {
+            names.extend_from_slice(name);
+            names.push(0);
+        }
+        reply_xattr(&names, size, reply);
+    }
 
-        let mut parent_attrs = match self.get_inode(parent) {
+    fn removexattr(&mut self, req: &Request<'_>, inode: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr() called with {:?} {:?}", inode, name);
+        if let Err(error_code) = self.check_writable() {
+            reply.error(error_code);
+            return;
+        }
+        let mut attrs = match self.get_inode(inode) {
             Ok(attrs) => attrs,
             Err(error_code) => {
                 reply.error(error_code);
                 return;
             }
         };
-
-        if !check_access(
-            parent_attrs.uid,
-            parent_attrs.gid,
-            parent_attrs.mode,
-            req.uid(),
-            req.gid(),
-            libc::W_OK,
-        ) {
-            reply.error(libc::EACCES);
+        if let Err(error_code) = xattr_access_check(name.as_bytes(), libc::W_OK, &attrs, req) {
+            reply.error(error_code);
             return;
         }
-
-        let uid = req.uid();
-        // "Sticky bit" handling
-        if parent_attrs.mode & libc::S_ISVTX as u16 != 0
-            && uid != 0
-            && uid != parent_attrs.uid
-            && uid != attrs.uid
-        {
-            reply.error(libc::EACCES);
+        if attrs.xattrs.remove(name.as_bytes()).is_none() {
+            reply.error(libc::ENODATA);
             return;
         }
+        if is_object_header_xattr(name.as_bytes()) {
+            self.mark_header_change(&mut attrs);
+        }
+        attrs.last_metadata_changed = time_now();
+        if let Err(error_code) = self.write_inode(&attrs) {
+            reply.error(error_code);
+            return;
+        }
+        reply.ok();
+    }
+}
 
-        parent_attrs.last_metadata_changed = time_now();
-        parent_attrs.last_modified = time_now();
-        self.write_inode(&parent_attrs);
+// Answer an xattr read, a zero size asks how large the buffer has to be
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
 
-        attrs.hardlinks -= 1;
-        attrs.last_metadata_changed = time_now();
-        self.write_inode(&attrs);
-        self.gc_inode(&attrs);
+fn is_object_header_xattr(name: &[u8]) -> bool {
+    name == CACHE_CONTROL_XATTR || name == CONTENT_DISPOSITION_XATTR
+}
 
-        let mut entries = self.get_directory_content(parent).unwrap();
-        entries.remove(name.as_bytes());
-        self.write_directory_content(parent, entries);
+// The headers a file's user.s3.* xattrs ask its object to be uploaded with
+fn object_headers(xattrs: &BTreeMap<Vec<u8>, Vec<u8>>) -> ObjectHeaders {
+    let header = |name: &[u8]| xattrs.get(name).map(|value| String::from_utf8_lossy(value).into_owned());
+    ObjectHeaders {
+        cache_control: header(CACHE_CONTROL_XATTR),
+        content_disposition: header(CONTENT_DISPOSITION_XATTR),
+    }
+}
 
-        reply.ok();
+// Take the user.s3.* xattrs from the headers the bucket reports for an object
+fn load_object_headers(xattrs: &mut BTreeMap<Vec<u8>, Vec<u8>>, metadata: &Metadata) {
+    for (name, value) in [
+        (CACHE_CONTROL_XATTR, metadata.cache_control()),
+        (CONTENT_DISPOSITION_XATTR, metadata.content_disposition()),
+    ] {
+        match value {
+            Some(value) => xattrs.insert(name.to_vec(), value.as_bytes().to_vec()),
+            None => xattrs.remove(name),
+        };
+    }
+}
+
+// Access rules per xattr namespace. Only root may touch "trusted.*" and "system.*", or change
+// "security.*". "user.*" follows the file's permission bits.
+fn xattr_access_check(name: &[u8], access_mask: i32, attrs: &InodeAttributes, req: &Request<'_>) -> Result<(), c_int> {
+    if name.starts_with(b"user.") {
+        if check_access(attrs.uid, attrs.gid, attrs.mode, req.uid(), req.gid(), access_mask) {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    } else if name.starts_with(b"security.") {
+        if access_mask == libc::R_OK || req.uid() == 0 {
+            Ok(())
+        } else {
+            Err(libc::EPERM)
+        }
+    } else if name.starts_with(b"trusted.") || name.starts_with(b"system.") {
+        if req.uid() == 0 {
+            Ok(())
+        } else {
+            Err(libc::EPERM)
+        }
+    } else {
+        Err(libc::ENOTSUP)
+    }
+}
+
+// Decode an inode file of the current format or of any earlier one
+fn decode_inode(data: &[u8]) -> Result<InodeAttributes, String> {
+    let (magic, version): ([u8; 4], u32) = bincode::deserialize(data).map_err(|e| e.to_string())?;
+    if magic != INODE_MAGIC {
+        // Before the header, tell the two layouts apart by which one takes up the whole file
+        let exact = bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes();
+        return match exact.deserialize::<InodeAttributesV1>(data) {
+            Ok(v1) => Ok(upgrade_v1(v1)),
+            Err(_) => exact
+                .deserialize::<InodeAttributesV0>(data)
+                .map(|v0| upgrade_v1(v0.into()))
+                .map_err(|e| format!("not an inode file of any known format: {}", e)),
+        };
+    }
+    let body = &data[8..];
+    let attrs = match version {
+        INODE_FORMAT_VERSION => bincode::deserialize(body),
+        4 => bincode::deserialize::<InodeAttributesV4>(body).map(InodeAttributes::from),
+        3 => bincode::deserialize::<InodeAttributesV3>(body).map(|v3| InodeAttributesV4::from(v3).into()),
+        2 => bincode::deserialize::<InodeAttributesV2>(body)
+            .map(|v2| InodeAttributesV4::from(InodeAttributesV3::from(v2)).into()),
+        1 => bincode::deserialize::<InodeAttributesV1>(body).map(upgrade_v1),
+        _ => return Err(format!("unsupported inode format version {}", version)),
+    };
+    attrs.map_err(|e| e.to_string())
+}
+
+fn upgrade_v1(v1: InodeAttributesV1) -> InodeAttributes {
+    InodeAttributesV4::from(InodeAttributesV3::from(InodeAttributesV2::from(v1))).into()
+}
+
+// Copy with the kernel's copy_file_range, which reflinks on copy-on-write filesystems so
+// duplicating a large cached file is nearly instant
+fn copy_between_files(src: &Path, src_offset: i64, dest: &Path, dest_offset: i64, len: u64) -> io::Result<u64> {
+    let src_file = File::open(src)?;
+    let dest_file = OpenOptions::new().write(true).open(dest)?;
+    let mut src_pos = src_offset;
+    let mut dest_pos = dest_offset;
+    let mut copied: u64 = 0;
+    while copied < len {
+        let result = unsafe {
+            libc::copy_file_range(
+                src_file.as_raw_fd(),
+                &mut src_pos,
+                dest_file.as_raw_fd(),
+                &mut dest_pos,
+                min(len - copied, usize::MAX as u64) as usize,
+                0,
+            )
+        };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if result == 0 {
+            break;
+        }
+        copied += result as u64;
+    }
+    Ok(copied)
+}
+
+// Compare by address only, the same type may have more than one vtable
+fn same_backend(a: &dyn StorageBackend, b: &dyn StorageBackend) -> bool {
+    std::ptr::eq(a as *const dyn StorageBackend as *const u8, b as *const dyn StorageBackend as *const u8)
+}
+
+fn segment_key(key: &str, index: usize) -> String {
+    format!("{}{}{:08}", key, SEGMENTS_SUFFIX, index)
+}
+
+// Stands in for the md5 of a file stored as segments once the cache holds all of them
+fn segments_marker(count: usize) -> String {
+    format!("segments:{}", count)
+}
+
+// Walk `path`, relative to the mount root, the way the kernel would, following every symbolic
+// link on the way. `lookup(dir, name)` gives an entry's inode and, for a symbolic link, its
+// target. Fails with ELOOP once more than `max_depth` links were followed. Walks that leave the
+// mount, through ".." above the root or an absolute target, or reach a missing entry end
+// without an error, as the kernel resolves those.
+fn walk_symlinks<F>(path: &[u8], max_depth: u32, mut lookup: F) -> Result<(), c_int>
+where
+    F: FnMut(Inode, &[u8]) -> Result<(Inode, Option<Vec<u8>>), c_int>,
+{
+    let mut dirs = vec![FUSE_ROOT_ID];
+    // Components still to walk, the next one last
+    let mut pending: Vec<Vec<u8>> = path.split(|&byte| byte == b'/').rev().map(<[u8]>::to_vec).collect();
+    let mut followed = 0;
+    while let Some(name) = pending.pop() {
+        match name.as_slice() {
+            b"" | b"." => continue,
+            b".." if dirs.len() > 1 => {
+                dirs.pop();
+                continue;
+            }
+            b".." => return Ok(()),
+            _ => {}
+        }
+        let (inode, target) = match lookup(*dirs.last().unwrap(), &name) {
+            Ok(entry) => entry,
+            Err(_) => return Ok(()),
+        };
+        match target {
+            Some(target) => {
+                followed += 1;
+                if followed > max_depth {
+                    return Err(libc::ELOOP);
+                }
+                if target.starts_with(b"/") {
+                    return Ok(());
+                }
+                pending.extend(target.split(|&byte| byte == b'/').rev().map(<[u8]>::to_vec));
+            }
+            None => dirs.push(inode),
+        }
+    }
+    Ok(())
+}
+
+// The ranges, as (offset, length), --prefetch-head-bytes and --prefetch-tail-bytes fetch of a
+// file of `size` bytes. Empty ranges are left out and overlapping ones merged.
+fn prefetch_ranges(size: u64, head: u64, tail: u64) -> Vec<(u64, u64)> {
+    let head = head.min(size);
+    let tail_start = size - tail.min(size);
+    if tail_start <= head {
+        return if size > 0 && (head > 0 || tail > 0) { vec![(0, size)] } else { vec![] };
     }
+    [(0, head), (tail_start, size - tail_start)].into_iter().filter(|(_, len)| *len > 0).collect()
+}
+
+// Serialize into a sibling temp file and rename it over the target, so a crash mid-write
+// leaves either the old or the new contents on disk, never a truncated file
+fn write_atomically<T: Serialize>(path: &Path, value: &T) -> bincode::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    bincode::serialize_into(&file, value)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
 
+// Ask the filesystem to drop its clean cached contents every time the process receives SIGUSR2
+pub(crate) fn watch_sigusr2(trigger: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut user2 = signal(SignalKind::user_defined2()).unwrap();
+            while user2.recv().await.is_some() {
+                info!("SIGUSR2 received, invalidating the cache on the next request");
+                trigger.store(true, Ordering::SeqCst);
+            }
+        });
+    });
+}
 
+// Get files queued by --writeback-delay uploaded once their delay has passed, even when nothing
+// else reaches the mount. Uploads stay on the filesystem's own thread like every other request,
+// this one only looks up WRITEBACK_WAKEUP_NAME in the mount root, which lookup() answers after
+// draining the queue.
+pub(crate) fn watch_upload_queue(queue: Arc<Mutex<HashMap<u64, Instant>>>, delay: Duration, mountpoint: String) {
+    thread::spawn(move || {
+        let wakeup = Path::new(&mountpoint).join(OsStr::from_bytes(WRITEBACK_WAKEUP_NAME));
+        loop {
+            let oldest = queue.lock().unwrap().values().min().copied();
+            let wait = match oldest {
+                Some(queued) => (queued + delay).saturating_duration_since(Instant::now()),
+                None => delay,
+            };
+            // At most one wakeup a second, in case the queue isn't drained
+            thread::sleep(wait.max(Duration::from_secs(1)));
+            let due = queue.lock().unwrap().values().any(|queued| queued.elapsed() >= delay);
+            if due {
+                // Always ENOENT, the lookup itself is what matters
+                let _ = fs::symlink_metadata(&wakeup);
+            }
+        }
+    });
+}
 
+// EROFS from the --readonly-after deadline on
+fn writable_at(readonly_after: Option<SystemTime>, now: SystemTime) -> Result<(), c_int> {
+    match readonly_after {
+        Some(deadline) if now >= deadline => Err(libc::EROFS),
+        _ => Ok(()),
+    }
 }
 
 fn clear_suid_sgid(attr: &mut InodeAttributes) {
@@ -914,19 +4738,36 @@ fn as_file_kind(mut mode: u32) -> FileKind {
         return FileKind::File;
     } else if mode == libc::S_IFDIR as u32 {
         return FileKind::Directory;
+    } else if mode == libc::S_IFLNK as u32 {
+        return FileKind::Symlink;
     } else {
         unimplemented!("{}", mode);
     }
 }
 
-fn creation_gid(parent: &InodeAttributes, gid: u32) -> u32 {
-    if parent.mode & libc::S_ISGID as u16 != 0 {
+// With BSD group semantics new entries always take the directory's group, on Linux only
+// when the directory is SGID
+fn creation_gid(parent: &InodeAttributes, gid: u32, bsd_groups: bool) -> u32 {
+    if bsd_groups || parent.mode & libc::S_ISGID as u16 != 0 {
         return parent.gid;
     }
 
     gid
 }
 
+// What tells two versions of an object apart, kept as the md5 of a clean cached file: the md5
+// or else the etag, and size and mtime for backends reporting neither
+fn object_version(metadata: &Metadata) -> String {
+    match metadata.content_md5().or_else(|| metadata.etag()) {
+        Some(version) => version.to_string(),
+        None => format!(
+            "{}@{}",
+            metadata.content_length(),
+            metadata.last_modified().map_or(0, |time| time.unix_timestamp_nanos())
+        ),
+    }
+}
+
 fn time_from_offsetdatatime(dt: Option<OffsetDateTime>) -> (i64, u32) {
     dt.map(|dt| {
         let timestamp_secs = dt.unix_timestamp();
@@ -941,10 +4782,517 @@ fn list_directory(path: &str) -> Option<&str> {
     last_directory
 }
 
-fn get_key_by_value<'a, K, V>(map: &'a BTreeMap<K, V>, value: &V) -> Option<&'a K>
-where
-    K: Ord,
-    V: PartialEq,
-{
-    map.iter().find(|(_, v)| v == &value).map(|(k, _)| k)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::MemoryCache;
+    use crate::s3util::tests::MemoryBackend;
+    use crate::s3util::DownloadOptions;
+
+    // A filesystem over an empty in-memory bucket, with the root set up as init leaves it
+    fn test_fs(name: &str, configure: impl FnOnce(&mut FsOptions)) -> S3FS {
+        test_fs_on(name, MemoryBackend::new(DownloadOptions::default()), configure)
+    }
+
+    fn test_fs_on(name: &str, worker: MemoryBackend, configure: impl FnOnce(&mut FsOptions)) -> S3FS {
+        let data_dir = std::env::temp_dir().join(format!("rusty-s3fs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        for dir in ["inodes", "contents", "objects"] {
+            fs::create_dir_all(data_dir.join(dir)).unwrap();
+        }
+        let mut options = FsOptions {
+            direct_io: false,
+            show_meta_files: false,
+            immutable_globs: vec![],
+            discovery_depth: None,
+            origin_fill: false,
+            max_write: None,
+            max_readahead: None,
+            readdir_order: ReaddirOrder::Name,
+            kernel_writeback: false,
+            serve_cached: false,
+            dedup_cache: false,
+            readonly_after: None,
+            oversize_policy: OversizePolicy::Stream,
+            metadata_only: false,
+            single_object: None,
+            bsd_groups: false,
+            limits: Arc::new(Limits::new(None, None, 0)),
+            append_segments: false,
+            readahead_blocks: None,
+            prefetch_head_bytes: 0,
+            prefetch_tail_bytes: 0,
+            hardlink_policy: HardlinkPolicy::Canonical,
+            stream_reads: false,
+            writeback_delay: None,
+            fsync_on_close: FsyncOnClose::DirtyOnly,
+            shutdown_grace: None,
+            keep_marker: ".keep".to_string(),
+            max_symlink_depth: 40,
+        };
+        configure(&mut options);
+        let fs = S3FS::new(
+            data_dir.to_str().unwrap().to_string(),
+            options,
+            Box::new(worker),
+            None,
+            Box::new(MemoryCache::new()),
+        );
+        fs.write_inode(&test_attrs(FUSE_ROOT_ID, FileKind::Directory)).unwrap();
+        let mut entries = BTreeMap::new();
+        entries.insert(b".".to_vec(), (FUSE_ROOT_ID, FileKind::Directory));
+        entries.insert(b"..".to_vec(), (FUSE_ROOT_ID, FileKind::Directory));
+        fs.write_directory_content(FUSE_ROOT_ID, entries).unwrap();
+        fs
+    }
+
+    // Attributes of a listed entry, that lookups take without asking the bucket
+    fn test_attrs(inode: Inode, kind: FileKind) -> InodeAttributes {
+        let mut attrs = upgrade_v1(v1_fixture());
+        attrs.inode = inode;
+        attrs.kind = kind;
+        attrs.size = 0;
+        attrs.md5 = "cached".to_string();
+        attrs.expanded = true;
+        attrs.dirty = false;
+        if kind == FileKind::Directory {
+            attrs.mode = 0o755;
+            attrs.hardlinks = 2;
+        }
+        attrs
+    }
+
+    // Replace a file's cached contents, as writes through the mount would
+    fn write_local(fs: &S3FS, inode: Inode, data: &[u8]) {
+        fs.cache.truncate(inode, 0).unwrap();
+        fs.cache.write_range(inode, 0, data).unwrap();
+        let mut attrs = fs.get_inode(inode).unwrap();
+        attrs.size = data.len() as u64;
+        attrs.dirty = true;
+        fs.write_inode(&attrs).unwrap();
+    }
+
+    // A file in the root with `len` bytes cached that match its object, last opened at `opened`
+    fn cached_file(fs: &S3FS, name: &str, len: u64, opened: Instant) -> Inode {
+        let inode = add_entry(fs, FUSE_ROOT_ID, name, FileKind::File);
+        fs.cache.truncate(inode, len).unwrap();
+        let mut attrs = fs.get_inode(inode).unwrap();
+        attrs.size = len;
+        fs.write_inode(&attrs).unwrap();
+        fs.cache_lru.lock().unwrap().insert(inode, opened);
+        inode
+    }
+
+    // Add an entry to a directory of a test_fs, as a listing would
+    fn add_entry(fs: &S3FS, parent: Inode, name: &str, kind: FileKind) -> Inode {
+        let inode = fs.allocate_next_inode().unwrap();
+        fs.write_inode(&test_attrs(inode, kind)).unwrap();
+        if kind == FileKind::Directory {
+            let mut entries = BTreeMap::new();
+            entries.insert(b".".to_vec(), (inode, FileKind::Directory));
+            entries.insert(b"..".to_vec(), (parent, FileKind::Directory));
+            fs.write_directory_content(inode, entries).unwrap();
+        }
+        let mut entries = fs.get_directory_content(parent).unwrap();
+        entries.insert(name.as_bytes().to_vec(), (inode, kind));
+        fs.write_directory_content(parent, entries).unwrap();
+        inode
+    }
+
+    fn v1_fixture() -> InodeAttributesV1 {
+        InodeAttributesV1 {
+            inode: 7,
+            open_file_handles: 0,
+            size: 42,
+            last_accessed: (100, 1),
+            last_modified: (200, 2),
+            last_metadata_changed: (300, 3),
+            kind: FileKind::File,
+            mode: 0o644,
+            hardlinks: 1,
+            uid: 1000,
+            gid: 1000,
+            // Local changes that were never uploaded
+            md5: "".to_string(),
+            expanded: false,
+        }
+    }
+
+    fn with_header<T: Serialize>(version: u32, attrs: &T) -> Vec<u8> {
+        bincode::serialize(&(INODE_MAGIC, version, attrs)).unwrap()
+    }
+
+    fn assert_upgraded(attrs: InodeAttributes, expanded: bool) {
+        assert_eq!(attrs.inode, 7);
+        assert_eq!(attrs.size, 42);
+        assert_eq!(attrs.last_modified, (200, 2));
+        assert_eq!(attrs.created, (200, 2));
+        assert!(attrs.kind == FileKind::File);
+        assert_eq!(attrs.mode, 0o644);
+        assert_eq!((attrs.uid, attrs.gid), (1000, 1000));
+        assert!(attrs.md5.is_empty());
+        // get_inode clears it again when there are no cached contents
+        assert!(attrs.dirty);
+        assert_eq!(attrs.expanded, expanded);
+        assert!(attrs.xattrs.is_empty());
+        assert!(attrs.segments.is_empty());
+    }
+
+    #[test]
+    fn decode_inode_reads_the_current_format() {
+        let attrs = upgrade_v1(v1_fixture());
+        let decoded = decode_inode(&with_header(INODE_FORMAT_VERSION, &attrs)).unwrap();
+        assert_upgraded(decoded, false);
+    }
+
+    #[test]
+    fn decode_inode_upgrades_versioned_layouts() {
+        let v1 = with_header(1, &v1_fixture());
+        let v2 = with_header(2, &InodeAttributesV2::from(v1_fixture()));
+        let v3 = with_header(3, &InodeAttributesV3::from(InodeAttributesV2::from(v1_fixture())));
+        let v4 = with_header(4, &InodeAttributesV4::from(InodeAttributesV3::from(InodeAttributesV2::from(v1_fixture()))));
+        for data in [v1, v2, v3, v4] {
+            assert_upgraded(decode_inode(&data).unwrap(), false);
+        }
+    }
+
+    #[test]
+    fn decode_inode_upgrades_files_without_a_header() {
+        let v1 = v1_fixture();
+        let v0 = InodeAttributesV0 {
+            inode: v1.inode,
+            open_file_handles: v1.open_file_handles,
+            size: v1.size,
+            last_accessed: v1.last_accessed,
+            last_modified: v1.last_modified,
+            last_metadata_changed: v1.last_metadata_changed,
+            kind: v1.kind,
+            mode: v1.mode,
+            hardlinks: v1.hardlinks,
+            uid: v1.uid,
+            gid: v1.gid,
+            md5: v1.md5.clone(),
+        };
+        assert_upgraded(decode_inode(&bincode::serialize(&v1).unwrap()).unwrap(), false);
+        // Listed eagerly at mount back then
+        assert_upgraded(decode_inode(&bincode::serialize(&v0).unwrap()).unwrap(), true);
+    }
+
+    #[test]
+    fn decode_inode_rejects_unknown_and_truncated_files() {
+        let newer = with_header(INODE_FORMAT_VERSION + 1, &v1_fixture());
+        assert!(decode_inode(&newer).is_err());
+        let current = with_header(INODE_FORMAT_VERSION, &upgrade_v1(v1_fixture()));
+        assert!(decode_inode(&current[..current.len() - 3]).is_err());
+        assert!(decode_inode(b"S3F").is_err());
+    }
+
+    #[test]
+    fn fsync_on_close_policies() {
+        // dirty-only uploads changed files, right away or through the writeback queue
+        assert_eq!(FsyncOnClose::DirtyOnly.on_close(true, false), CloseUpload::Now);
+        assert_eq!(FsyncOnClose::DirtyOnly.on_close(true, true), CloseUpload::Queue);
+        assert_eq!(FsyncOnClose::DirtyOnly.on_close(false, false), CloseUpload::Skip);
+        // always uploads on every close, without waiting for the queue
+        assert_eq!(FsyncOnClose::Always.on_close(false, false), CloseUpload::Now);
+        assert_eq!(FsyncOnClose::Always.on_close(true, true), CloseUpload::Now);
+        // never leaves changed files to the writeback queue
+        assert_eq!(FsyncOnClose::Never.on_close(true, true), CloseUpload::Queue);
+        assert_eq!(FsyncOnClose::Never.on_close(false, true), CloseUpload::Skip);
+    }
+
+    // A tree of the root (1) holding the directory "d" (2) and the given links
+    fn walk(path: &str, max_depth: u32, links: &[(&str, &str)]) -> Result<(), c_int> {
+        walk_symlinks(path.as_bytes(), max_depth, |dir, name| {
+            if dir == FUSE_ROOT_ID && name == b"d" {
+                return Ok((2, None));
+            }
+            let found = links.iter().position(|(link, _)| link.as_bytes() == name).ok_or(libc::ENOENT)?;
+            Ok((10 + found as u64, Some(links[found].1.as_bytes().to_vec())))
+        })
+    }
+
+    #[test]
+    fn symlink_cycles_fail_with_eloop() {
+        assert_eq!(walk("a", 40, &[("a", "b"), ("b", "a")]), Err(libc::ELOOP));
+        assert_eq!(walk("a", 40, &[("a", "a")]), Err(libc::ELOOP));
+        // Through a directory and back up
+        assert_eq!(walk("a", 40, &[("a", "d/../a")]), Err(libc::ELOOP));
+    }
+
+    #[test]
+    fn symlink_chains_within_the_depth_resolve() {
+        assert_eq!(walk("a", 2, &[("a", "b"), ("b", "d")]), Ok(()));
+        assert_eq!(walk("a", 1, &[("a", "b"), ("b", "d")]), Err(libc::ELOOP));
+        // Dangling, absolute and escaping targets aren't loops
+        assert_eq!(walk("a", 40, &[("a", "missing")]), Ok(()));
+        assert_eq!(walk("a", 40, &[("a", "/a")]), Ok(()));
+        assert_eq!(walk("a", 40, &[("a", "../a")]), Ok(()));
+    }
+
+    #[test]
+    fn object_header_xattrs_become_upload_headers() {
+        let mut xattrs = BTreeMap::new();
+        assert_eq!(object_headers(&xattrs), ObjectHeaders::default());
+        xattrs.insert(CACHE_CONTROL_XATTR.to_vec(), b"max-age=3600".to_vec());
+        xattrs.insert(b"user.comment".to_vec(), b"not a header".to_vec());
+        assert_eq!(
+            object_headers(&xattrs),
+            ObjectHeaders {
+                cache_control: Some("max-age=3600".to_string()),
+                content_disposition: None,
+            }
+        );
+        xattrs.insert(CONTENT_DISPOSITION_XATTR.to_vec(), b"attachment".to_vec());
+        assert_eq!(object_headers(&xattrs).content_disposition.as_deref(), Some("attachment"));
+    }
+
+    #[test]
+    fn prefetch_ranges_cover_head_and_tail() {
+        assert_eq!(prefetch_ranges(1000, 0, 0), vec![]);
+        assert_eq!(prefetch_ranges(1000, 100, 0), vec![(0, 100)]);
+        assert_eq!(prefetch_ranges(1000, 0, 10), vec![(990, 10)]);
+        assert_eq!(prefetch_ranges(1000, 100, 10), vec![(0, 100), (990, 10)]);
+        // Ranges meeting or past the size are one fetch of the whole file
+        assert_eq!(prefetch_ranges(1000, 600, 400), vec![(0, 1000)]);
+        assert_eq!(prefetch_ranges(1000, 5000, 0), vec![(0, 1000)]);
+        assert_eq!(prefetch_ranges(0, 100, 100), vec![]);
+    }
+
+    // Fails partway through, after some bytes were written
+    struct FailingValue;
+
+    impl Serialize for FailingValue {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::{Error, SerializeTuple};
+            let mut tuple = serializer.serialize_tuple(2)?;
+            tuple.serialize_element(&vec![7u8; 4096])?;
+            Err(S::Error::custom("interrupted"))
+        }
+    }
+
+    #[test]
+    fn write_atomically_never_exposes_a_partial_file() {
+        let dir = std::env::temp_dir().join(format!("rusty-s3fs-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("value");
+        let (short, long) = (vec![1u8; 16], vec![2u8; 64 * 1024]);
+        write_atomically(&path, &short).unwrap();
+
+        // Readers only ever see one of the complete values while they are replaced
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (path, done, short, long) = (path.clone(), done.clone(), short.clone(), long.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let value: Vec<u8> = bincode::deserialize(&fs::read(&path).unwrap()).unwrap();
+                    assert!(value == short || value == long);
+                }
+            })
+        };
+        for round in 0..50 {
+            write_atomically(&path, if round % 2 == 0 { &long } else { &short }).unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap();
+
+        // A write that fails leaves the previous value in place
+        assert!(write_atomically(&path, &FailingValue).is_err());
+        let value: Vec<u8> = bincode::deserialize(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(value, short);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_follow_the_names_a_file_was_met_under() {
+        let fs = test_fs("linked-keys", |_| {});
+        let dir = add_entry(&fs, FUSE_ROOT_ID, "dir", FileKind::Directory);
+        let file = add_entry(&fs, dir, "file", FileKind::File);
+        // A second name, in a directory that sorts before the first
+        let mut attrs = fs.get_inode(file).unwrap();
+        attrs.hardlinks = 2;
+        fs.write_inode(&attrs).unwrap();
+        let mut entries = fs.get_directory_content(FUSE_ROOT_ID).unwrap();
+        entries.insert(b"alias".to_vec(), (file, FileKind::File));
+        fs.write_directory_content(FUSE_ROOT_ID, entries).unwrap();
+
+        // The name the file was looked up under first stays its key
+        fs.lookup_name(dir, OsStr::new("file")).unwrap();
+        fs.lookup_name(FUSE_ROOT_ID, OsStr::new("alias")).unwrap();
+        assert_eq!(fs.get_filename_from_inode(file).unwrap(), "dir/file");
+        assert_eq!(fs.linked_keys(file, false).unwrap(), vec!["dir/file", "alias"]);
+
+        // Renaming the directory moves the key along without updating the file's names
+        let mut entries = fs.get_directory_content(FUSE_ROOT_ID).unwrap();
+        entries.remove(b"dir".as_ref());
+        entries.insert(b"moved".to_vec(), (dir, FileKind::Directory));
+        fs.write_directory_content(FUSE_ROOT_ID, entries).unwrap();
+        assert_eq!(fs.get_filename_from_inode(file).unwrap(), "moved/file");
+
+        // A name the directory no longer lists is dropped
+        let mut entries = fs.get_directory_content(dir).unwrap();
+        entries.remove(b"file".as_ref());
+        fs.write_directory_content(dir, entries).unwrap();
+        assert_eq!(fs.get_filename_from_inode(file).unwrap(), "alias");
+        fs::remove_dir_all(&fs.data_dir).unwrap();
+    }
+
+    #[test]
+    fn directories_are_kept_with_an_object_when_markers_are_refused() {
+        let mut worker = MemoryBackend::new(DownloadOptions::default());
+        let mut fs = test_fs_on("dir-markers", worker.clone(), |_| {});
+        let rt = Runtime::new().unwrap();
+        fs.create_dir_marker("plain/").unwrap();
+        assert!(rt.block_on(worker.operator().read("plain/")).unwrap().is_empty());
+        assert!(!fs.keep_markers);
+
+        worker.refuse_markers = true;
+        let mut fs = test_fs_on("keep-markers", worker.clone(), |_| {});
+        fs.create_dir_marker("empty/").unwrap();
+        assert!(fs.keep_markers);
+        // Later directories go straight to the object
+        fs.create_dir_marker("empty/nested/").unwrap();
+        assert!(rt.block_on(worker.operator().read("empty/.keep")).unwrap().is_empty());
+        assert!(rt.block_on(worker.operator().read("empty/nested/.keep")).unwrap().is_empty());
+        assert_eq!(rt.block_on(fs.list_backend("empty/")).unwrap(), vec!["nested/"]);
+        assert!(rt.block_on(fs.list_backend("empty/nested/")).unwrap().is_empty());
+
+        // The empty directory is still there for the next mount
+        let remounted = test_fs_on("keep-markers-remount", worker, |_| {});
+        assert!(rt.block_on(remounted.list_backend("")).unwrap().contains(&"empty/".to_string()));
+        for fs in [fs, remounted] {
+            fs::remove_dir_all(&fs.data_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn queued_uploads_coalesce_and_drain_at_unmount() {
+        let worker = MemoryBackend::new(DownloadOptions::default());
+        let delay = Duration::from_millis(200);
+        let mut fs = test_fs_on("writeback-queue", worker.clone(), |options| options.writeback_delay = Some(delay));
+        let rt = Runtime::new().unwrap();
+        let uploaded = |key: &str| rt.block_on(worker.operator().read(key)).ok();
+        let file = add_entry(&fs, FUSE_ROOT_ID, "file", FileKind::File);
+
+        // Closing the file again keeps its place in the queue, it is uploaded once, as last written
+        write_local(&fs, file, b"one");
+        fs.queue_upload(file);
+        thread::sleep(delay / 2);
+        write_local(&fs, file, b"two");
+        fs.queue_upload(file);
+        fs.upload_queued_files();
+        assert_eq!(uploaded("file"), None);
+        thread::sleep(delay / 2 + Duration::from_millis(20));
+        fs.upload_queued_files();
+        assert_eq!(uploaded("file").as_deref(), Some(b"two".as_ref()));
+        assert!(fs.upload_queue.lock().unwrap().is_empty());
+
+        // Unmounting doesn't wait for the delay
+        write_local(&fs, file, b"three");
+        fs.queue_upload(file);
+        fs.destroy();
+        assert_eq!(uploaded("file").as_deref(), Some(b"three".as_ref()));
+        fs::remove_dir_all(&fs.data_dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_inode_files_fail_with_eio() {
+        let fs = test_fs("truncated-inode", |_| {});
+        let file = add_entry(&fs, FUSE_ROOT_ID, "file", FileKind::File);
+        let path = Path::new(&fs.data_dir).join("inodes").join(file.to_string());
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() / 2]).unwrap();
+        assert_eq!(fs.get_inode(file).err(), Some(libc::EIO));
+        // A missing one is still just missing
+        fs::remove_file(&path).unwrap();
+        assert_eq!(fs.get_inode(file).err(), Some(libc::ENOENT));
+        fs::remove_dir_all(&fs.data_dir).unwrap();
+    }
+
+    #[test]
+    fn mutations_fail_from_the_readonly_deadline_on() {
+        let now = SystemTime::now();
+        let minute = Duration::from_secs(60);
+        assert_eq!(writable_at(None, now), Ok(()));
+        assert_eq!(writable_at(Some(now + minute), now), Ok(()));
+        assert_eq!(writable_at(Some(now), now), Err(libc::EROFS));
+        assert_eq!(writable_at(Some(now - minute), now), Err(libc::EROFS));
+    }
+
+    #[test]
+    fn reloaded_cache_size_applies_to_eviction() {
+        let limits = Arc::new(Limits::new(None, None, 0));
+        let fs = test_fs("reload-cache-size", |options| options.limits = limits.clone());
+        let start = Instant::now();
+        let files: Vec<Inode> = (0..3)
+            .map(|i| cached_file(&fs, &format!("file{}", i), 10, start + Duration::from_secs(i)))
+            .collect();
+        fs.enforce_cache_size(files[2]);
+        assert!(files.iter().all(|inode| fs.cache.cached_len(*inode).is_some()));
+
+        let config = Path::new(&fs.data_dir).join("rusty-s3fs.conf");
+        fs::write(&config, "cache-size = 20\n").unwrap();
+        crate::config::reload(config.to_str().unwrap(), &limits).unwrap();
+        fs.enforce_cache_size(files[2]);
+        let cached: Vec<_> = files.iter().map(|inode| fs.cache.cached_len(*inode)).collect();
+        assert_eq!(cached, vec![None, Some(10), Some(10)]);
+        fs::remove_dir_all(&fs.data_dir).unwrap();
+    }
+
+    #[test]
+    fn new_entries_take_the_group_of_bsd_or_sgid_parents() {
+        let mut parent = test_attrs(FUSE_ROOT_ID, FileKind::Directory);
+        parent.gid = 100;
+        // Neither, the creator's group
+        assert_eq!(creation_gid(&parent, 1000, false), 1000);
+        assert_eq!(creation_gid(&parent, 1000, true), 100);
+        parent.mode |= libc::S_ISGID as u16;
+        assert_eq!(creation_gid(&parent, 1000, false), 100);
+        assert_eq!(creation_gid(&parent, 1000, true), 100);
+    }
+
+    #[test]
+    fn the_coldest_clean_files_are_evicted_down_to_the_cache_size() {
+        let fs = test_fs("eviction", |options| options.limits = Arc::new(Limits::new(Some(25), None, 0)));
+        let start = Instant::now();
+        let files: Vec<Inode> = (0..4)
+            .map(|i| cached_file(&fs, &format!("file{}", i), 10, start + Duration::from_secs(i)))
+            .collect();
+        let cached = |fs: &S3FS| -> Vec<bool> { files.iter().map(|inode| fs.cache.cached_len(*inode).is_some()).collect() };
+        fs.enforce_cache_size(files[3]);
+        assert_eq!(cached(&fs), vec![false, false, true, true]);
+        // Only the contents go, the next open downloads them again
+        let evicted = fs.get_inode(files[0]).unwrap();
+        assert_eq!(evicted.size, 10);
+        assert!(evicted.md5.is_empty());
+        fs::remove_dir_all(&fs.data_dir).unwrap();
+
+        // Files with local changes, open ones and the file about to be opened stay, even above the limit
+        let fs = test_fs("eviction-kept", |options| options.limits = Arc::new(Limits::new(Some(15), None, 0)));
+        let files: Vec<Inode> = (0..4)
+            .map(|i| cached_file(&fs, &format!("file{}", i), 10, start + Duration::from_secs(i)))
+            .collect();
+        write_local(&fs, files[0], &[b'x'; 10]);
+        let mut open = fs.get_inode(files[1]).unwrap();
+        open.open_file_handles = 1;
+        fs.write_inode(&open).unwrap();
+        fs.enforce_cache_size(files[2]);
+        assert_eq!(cached(&fs), vec![true, true, true, false]);
+        fs::remove_dir_all(&fs.data_dir).unwrap();
+    }
+
+    #[test]
+    fn mtime_order_lists_the_newest_entries_first() {
+        let fs = test_fs("mtime-order", |options| options.readdir_order = ReaddirOrder::Mtime);
+        for (name, modified) in [("a", (100, 0)), ("b", (300, 0)), ("c", (100, 500)), ("d", (200, 0))] {
+            let inode = add_entry(&fs, FUSE_ROOT_ID, name, FileKind::File);
+            let mut attrs = fs.get_inode(inode).unwrap();
+            attrs.last_modified = modified;
+            fs.write_inode(&attrs).unwrap();
+        }
+        let listing = fs.sort_listing(fs.name_ordered_listing(FUSE_ROOT_ID).unwrap());
+        let names: Vec<Vec<u8>> = listing.map(|(name, _, _)| name).collect();
+        assert_eq!(names, [&b"."[..], b"..", b"b", b"d", b"c", b"a"]);
+        fs::remove_dir_all(&fs.data_dir).unwrap();
+    }
+}
@@ -1,63 +1,326 @@
-use opendal::Operator;
-use opendal::services::Gcs;
+use opendal::{ErrorKind, Operator};
+use opendal::ops::OpWrite;
+use opendal::services::{Gcs, S3};
 use opendal::Metadata;
-use futures::TryStreamExt;
-use std::sync::Arc;
+use opendal::raw::HttpClient;
+use futures::{StreamExt, TryStreamExt};
+use async_trait::async_trait;
 // use std::task::{Context, Poll};
 // use futures::future::poll_fn;
 // use opendal::raw::oio::Read;
 // use std::ops::RangeBounds;
 // use std::error::Error;
-use tokio::sync::{Mutex, Semaphore};
-use tokio::fs::File;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::sync::{mpsc, Mutex};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 use tokio::task;
 // use tokio::runtime::Runtime;
+use std::fmt;
+use std::time::Duration;
+use std::cmp::min;
+use std::ops::Range;
+use log::{debug, warn};
+
+// Downloads of an object whose size keeps changing under us before the delivered bytes are trusted
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+// Content type of the small objects holding a symbolic link's target
+pub(crate) const SYMLINK_CONTENT_TYPE: &str = "application/x-s3fs-symlink";
+// Largest part S3 takes in a multipart upload
+const MAX_UPLOAD_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+// Scaled part sizes are rounded up to whole MiB
+const UPLOAD_PART_ALIGNMENT: u64 = 1024 * 1024;
+// Starts every certificate in a PEM file
+const PEM_CERTIFICATE_LABEL: &[u8] = b"-----BEGIN CERTIFICATE-----";
+
+// Connection pool settings for the HTTP client shared by every backend request
+#[derive(Clone)]
+pub(crate) struct HttpOptions {
+    pub max_idle_per_host: usize,
+    pub idle_timeout: Duration,
+    // Interval of TCP keepalive probes, which find dead peers of pooled connections
+    pub tcp_keepalive: Duration,
+    // Only bounds DNS resolution and the TCP/TLS handshake, not requests on an open connection
+    pub connect_timeout: Option<Duration>,
+    pub http2_only: bool,
+    // Extra root certificate for endpoints signed by a private CA
+    pub ca_cert: Option<reqwest::Certificate>,
+    // Accept any server certificate, only meant for testing against throwaway endpoints
+    pub insecure_skip_verify: bool,
+}
+
+impl HttpOptions {
+    fn client(&self) -> Result<HttpClient, opendal::Error> {
+        HttpClient::build(self.client_builder())
+    }
+
+    fn client_builder(&self) -> reqwest::ClientBuilder {
+        // Ask for the uncompressed representation, so a backend that compresses responses on
+        // the fly sends the logical content and the cached file matches the size stat reports
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::ACCEPT_ENCODING,
+            reqwest::header::HeaderValue::from_static("identity"),
+        );
+        let mut builder = reqwest::Client::builder()
+            .default_headers(headers)
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .pool_idle_timeout(self.idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if self.http2_only {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(cert) = &self.ca_cert {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        if self.insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        builder
+    }
+}
+
+// How objects are split up when they are downloaded or uploaded
+#[derive(Clone, Copy)]
+pub(crate) struct DownloadOptions {
+    // Bytes fetched by one range request, objects up to this size are read in a single request
+    pub block_size: u64,
+    // Blocks of one object fetched at the same time, and parts of one upload read ahead
+    pub threads: usize,
+    // Bytes sent by one part of a multipart upload, files up to this size are put in one request
+    pub upload_part_size: u64,
+    // Parts one multipart upload may have, larger files are sent in larger parts
+    pub max_upload_parts: u64,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            block_size: 64 * 1024 * 1024,
+            threads: 4,
+            upload_part_size: 64 * 1024 * 1024,
+            max_upload_parts: 10_000,
+        }
+    }
+}
+
+// Headers stored with an object when it is uploaded, for clients fetching it straight from the
+// bucket over HTTP
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ObjectHeaders {
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+}
+
+impl ObjectHeaders {
+    fn is_empty(&self) -> bool {
+        self.cache_control.is_none() && self.content_disposition.is_none()
+    }
+
+    fn write_options(&self) -> OpWrite {
+        let mut args = OpWrite::new();
+        if let Some(cache_control) = &self.cache_control {
+            args = args.with_cache_control(cache_control);
+        }
+        if let Some(content_disposition) = &self.content_disposition {
+            args = args.with_content_disposition(content_disposition);
+        }
+        args
+    }
+}
+
+// The part size uploading `size` (at least 1) bytes in at most `max_parts` parts, `part_size`
+// unless that takes too many
+fn scaled_part_size(size: u64, part_size: u64, max_parts: u64) -> WorkerResult<u64> {
+    if (size - 1) / part_size < max_parts {
+        return Ok(part_size);
+    }
+    let needed = (size - 1) / max_parts + 1;
+    let scaled = ((needed - 1) / UPLOAD_PART_ALIGNMENT + 1) * UPLOAD_PART_ALIGNMENT;
+    if scaled > MAX_UPLOAD_PART_SIZE {
+        return Err(WorkerError::Other(format!(
+            "{} bytes don't fit in {} parts of at most {} bytes",
+            size, max_parts, MAX_UPLOAD_PART_SIZE
+        )));
+    }
+    Ok(scaled)
+}
+
+// Read and parse a PEM encoded CA certificate, so a bad path fails at startup rather than on first request
+pub(crate) fn load_ca_cert(path: &str) -> Result<reqwest::Certificate, anyhow::Error> {
+    let pem = std::fs::read(path)?;
+    // With rustls the certificate is only decoded once it is added to a client, and anything
+    // that isn't a certificate is skipped without an error
+    if !pem.windows(PEM_CERTIFICATE_LABEL.len()).any(|window| window == PEM_CERTIFICATE_LABEL) {
+        return Err(anyhow::anyhow!("no PEM encoded certificate found"));
+    }
+    let cert = reqwest::Certificate::from_pem(&pem)?;
+    reqwest::Client::builder().add_root_certificate(cert.clone()).build()?;
+    Ok(cert)
+}
+
+// Errors from backend requests, classified so callers can pick an errno or decide to retry
+#[derive(Debug)]
+pub(crate) enum WorkerError {
+    NotFound(String),
+    PermissionDenied(String),
+    RateLimited(String),
+    // Transient failures talking to the backend, worth retrying
+    Network(String),
+    Other(String),
+}
+
+pub(crate) type WorkerResult<T> = Result<T, WorkerError>;
+
+impl WorkerError {
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            WorkerError::NotFound(_) => libc::ENOENT,
+            WorkerError::PermissionDenied(_) => libc::EACCES,
+            WorkerError::RateLimited(_) => libc::EAGAIN,
+            WorkerError::Network(_) | WorkerError::Other(_) => libc::EIO,
+        }
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerError::NotFound(msg) => write!(f, "not found: {}", msg),
+            WorkerError::PermissionDenied(msg) => write!(f, "permission denied: {}", msg),
+            WorkerError::RateLimited(msg) => write!(f, "rate limited: {}", msg),
+            WorkerError::Network(msg) => write!(f, "network error: {}", msg),
+            WorkerError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for WorkerError {}
+
+impl From<opendal::Error> for WorkerError {
+    fn from(err: opendal::Error) -> Self {
+        let msg = err.to_string();
+        match err.kind() {
+            ErrorKind::NotFound => WorkerError::NotFound(msg),
+            ErrorKind::PermissionDenied => WorkerError::PermissionDenied(msg),
+            ErrorKind::RateLimited => WorkerError::RateLimited(msg),
+            _ if err.is_temporary() => WorkerError::Network(msg),
+            _ => WorkerError::Other(msg),
+        }
+    }
+}
+
+// Failures writing the local copy of an object
+impl From<std::io::Error> for WorkerError {
+    fn from(err: std::io::Error) -> Self {
+        WorkerError::Other(err.to_string())
+    }
+}
+
+impl From<task::JoinError> for WorkerError {
+    fn from(err: task::JoinError) -> Self {
+        WorkerError::Other(err.to_string())
+    }
+}
 
 pub(crate) struct GcsWorker {
     bucket: String,
-    builder: Gcs,
+    // Built once and shared by every request, it is Arc-backed so clones are cheap
+    op: Operator,
+    download: DownloadOptions,
 }
 
 impl GcsWorker {
-    pub fn new(
+    // Unset endpoint, credential and scope keep opendal's defaults: the public GCS endpoint and
+    // application default credentials
+    pub fn with_config(
         bucket: String,
+        http: HttpOptions,
+        download: DownloadOptions,
+        endpoint: Option<&str>,
+        credential: Option<&str>,
+        scope: Option<&str>,
     ) -> GcsWorker {
         let mut builder = Gcs::default();
         builder.bucket(bucket.as_str());
-        // builder.endpoint("http://127.0.0.1:9000");
-        // builder.access_key_id("admin");
-        // builder.secret_access_key("password");
+        // The client keeps its connection pool alive across requests instead of reconnecting
+        // every time
+        builder.http_client(http.client().unwrap());
+        if let Some(endpoint) = endpoint {
+            builder.endpoint(endpoint);
+        }
+        // Path to a service account JSON key
+        if let Some(credential) = credential {
+            builder.credential_path(credential);
+        }
+        if let Some(scope) = scope {
+            builder.scope(scope);
+        }
         GcsWorker {
             bucket,
-            builder,
+            op: Operator::new(builder).unwrap().finish(),
+            download,
         }
     }
+}
 
-    pub async fn is_exist(&self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let op = Operator::new(self.builder.clone())?.finish();
-        let exist = op.is_exist(path).await?;
-        Ok(exist)
+pub(crate) struct S3Worker {
+    op: Operator,
+    download: DownloadOptions,
+}
+
+impl S3Worker {
+    // Credentials come from the usual AWS environment variables and profile files, an unset
+    // endpoint means AWS itself
+    pub fn with_config(
+        bucket: String,
+        http: HttpOptions,
+        download: DownloadOptions,
+        endpoint: Option<&str>,
+        region: Option<&str>,
+    ) -> S3Worker {
+        let mut builder = S3::default();
+        builder.bucket(bucket.as_str());
+        builder.http_client(http.client().unwrap());
+        if let Some(endpoint) = endpoint {
+            builder.endpoint(endpoint);
+        }
+        if let Some(region) = region {
+            builder.region(region);
+        }
+        S3Worker {
+            op: Operator::new(builder).unwrap().finish(),
+            download,
+        }
     }
+}
 
-    pub async fn get_stats(&self, path: &str) -> Result<Metadata, Box<dyn std::error::Error>> {
-        let op = Operator::new(self.builder.clone())?.finish();
-        let metadata = op.stat(path).await?;
-        Ok(metadata)
+// Object store requests made by the filesystem. Every backend is an opendal service, so the
+// requests are written once against the operator and implementations only build it.
+#[async_trait]
+pub(crate) trait StorageBackend: Send + Sync {
+    fn operator(&self) -> &Operator;
+
+    fn download_options(&self) -> DownloadOptions;
+
+    // Key of the object behind a path, for requests made on the operator directly
+    fn object_key(&self, path: &str) -> String {
+        path.to_string()
     }
 
-    pub async fn is_file(&self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let op = Operator::new(self.builder.clone())?.finish();
-        let metadata = op.stat(path).await?;
-        if metadata.is_file(){
-            Ok(true)
-        } else {
-            Ok(false)
-        }
-        
+    async fn is_exist(&self, path: &str) -> WorkerResult<bool> {
+        let exist = self.operator().is_exist(path).await?;
+        Ok(exist)
+    }
+
+    async fn get_stats(&self, path: &str) -> WorkerResult<Metadata> {
+        let metadata = self.operator().stat(path).await?;
+        Ok(metadata)
     }
 
-    // pub async fn get_data(&self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // pub async fn get_data(&self, path: &str) -> Result<Vec<u8>, WorkerError> {
     //     let op = Operator::new(self.builder.clone())?.finish();
     
     //     // Get the metadata and file size
@@ -108,66 +371,205 @@ impl GcsWorker {
     // }    
     
 
-    pub async fn get_data(
+    // Download an object into a local file and return the number of bytes written. The object
+    // can change between the stat that sizes the file and the reads that fill it, so a size
+    // mismatch downloads it again, and the last attempt trusts the bytes actually delivered.
+    async fn get_data(
         &self,
         path: &str,
         local_file_path: &str,
-    ) -> Result<u64, anyhow::Error> {
-        let op = Operator::new(self.builder.clone())?.finish();
-        let metadata = op.stat(path).await?;
-        let size = metadata.content_length();
-
-        // Create and initialize the file
-        let file = File::create(local_file_path).await?;
-        file.set_len(size).await?;
-        let file_mutex = Arc::new(Mutex::new(file));
-        let block_size = 64 * 1024 * 1024;
-        let num_blocks = (size as f64 / block_size as f64).ceil() as usize;
-        let num_threads = 4;
-        let semaphore = Arc::new(Semaphore::new(num_threads));
-        let mut tasks = Vec::with_capacity(num_blocks);
-        let builder_clone = self.builder.clone();
-        for i in 0..num_blocks {
-            let start = block_size * i as u64;
-            let end = std::cmp::min(start + block_size, size);
-            let range = start..end;
-            let semaphore_clone = Arc::clone(&semaphore);
-            let path_clone = path.to_owned();
-            let file_clone = Arc::clone(&file_mutex);
-            let builder_clone = builder_clone.clone();
-            let task = task::spawn(async move {
-                let _permit = semaphore_clone.acquire().await;
-                let op_c = Operator::new(builder_clone)?.finish();
-
-                let data = op_c.range_read(&path_clone, range).await?;
-                let mut file_clone = file_clone.lock().await;
-                file_clone.seek(SeekFrom::Start(start)).await?;
-                file_clone.write_all(&data).await?;
-
-                Ok::<(), anyhow::Error>(())
-            });
-
-            tasks.push(task);
-        }
-
-        let mut total_bytes_read: u64 = 0;
-        for result in futures::future::join_all(tasks).await {
-            match result {
-                Ok(_) => total_bytes_read += block_size,
-                Err(e) => return Err(e.into()),
+    ) -> WorkerResult<u64> {
+        let mut size = self.get_stats(path).await?.content_length();
+        let mut attempt = 1;
+        let delivered = loop {
+            let delivered = download_blocks(self, path, local_file_path, size, self.download_options()).await?;
+            let total: u64 = delivered.iter().sum();
+            let restat_size = self.get_stats(path).await?.content_length();
+            if total == size && restat_size == size {
+                return Ok(size);
+            }
+            warn!(
+                "{}: stat reported {} bytes ({} after download) but {} were delivered, attempt {} of {}",
+                path, size, restat_size, total, attempt, DOWNLOAD_ATTEMPTS
+            );
+            if attempt == DOWNLOAD_ATTEMPTS {
+                break delivered;
+            }
+            attempt += 1;
+            size = restat_size;
+        };
+
+        // Keep what was delivered as long as it's a prefix of the object, i.e. a short block
+        // is only followed by empty ones
+        let total: u64 = delivered.iter().sum();
+        let block_size = self.download_options().block_size;
+        let first_short = delivered
+            .iter()
+            .enumerate()
+            .position(|(i, bytes)| *bytes < min(block_size, size - block_size * i as u64));
+        if let Some(i) = first_short {
+            if delivered[i + 1..].iter().any(|bytes| *bytes > 0) {
+                return Err(WorkerError::Other(format!(
+                    "incomplete read of {}: {} of {} bytes delivered with holes",
+                    path,
+                    total,
+                    size
+                )));
             }
         }
-        // Return the total bytes read
-        Ok(total_bytes_read)
+        let file = OpenOptions::new().write(true).open(local_file_path).await?;
+        file.set_len(total).await?;
+        warn!("{}: using the {} delivered bytes instead of the reported {}", path, total, size);
+        Ok(total)
     }
 
+    // Read `len` bytes at `offset` straight from the object, for files too large to cache
+    async fn read_range(&self, path: &str, offset: u64, len: u64) -> WorkerResult<Vec<u8>> {
+        Ok(self.operator().range_read(path, offset..offset + len).await?)
+    }
 
-    
-    
+    async fn put_data(&self, path: &str, data: Vec<u8>) -> WorkerResult<()> {
+        self.operator().write(path, data).await?;
+        Ok(())
+    }
+
+    async fn put_data_with(&self, path: &str, data: Vec<u8>, headers: &ObjectHeaders) -> WorkerResult<()> {
+        if headers.is_empty() {
+            return self.put_data(path, data).await;
+        }
+        self.operator().write_with(path, headers.write_options(), data).await?;
+        Ok(())
+    }
+
+    // Upload a local file without holding all of it in memory. Files larger than one part go
+    // through a multipart upload fed part by part, with up to `threads` parts read ahead of the
+    // one being sent. Any failure aborts the upload, so no unfinished parts are left behind.
+    // Exactly `size` bytes are sent: holes in a sparse file read back as zeros, and a cache file
+    // shorter than the logical size is padded with zeros, so the object never comes out short.
+    // Files too large for max_upload_parts parts are sent in larger ones. `headers` can only be
+    // set by a single request upload, multipart uploads are made without them.
+    async fn put_file(&self, path: &str, local_file_path: &str, size: u64, headers: &ObjectHeaders) -> WorkerResult<()> {
+        let options = self.download_options();
+        let mut file = File::open(local_file_path).await?.take(size);
+        if size <= options.upload_part_size {
+            let mut data = Vec::with_capacity(size as usize);
+            file.read_to_end(&mut data).await?;
+            data.resize(size as usize, 0);
+            return self.put_data_with(path, data, headers).await;
+        }
+        if !headers.is_empty() {
+            warn!("Uploading {} in parts, without its cache-control and content-disposition headers", path);
+        }
+        let part_size = scaled_part_size(size, options.upload_part_size, options.max_upload_parts)?;
+        if part_size != options.upload_part_size {
+            debug!("Uploading {} in parts of {} bytes to stay within {} parts", path, part_size, options.max_upload_parts);
+        }
+        let (sender, mut parts) = mpsc::channel(options.threads);
+        let reader = task::spawn(async move {
+            let mut remaining = size;
+            loop {
+                let wanted = remaining.min(part_size);
+                let mut part = Vec::with_capacity(wanted as usize);
+                let read = (&mut file).take(wanted).read_to_end(&mut part).await.map(|_| {
+                    part.resize(wanted as usize, 0);
+                    wanted
+                });
+                remaining -= wanted;
+                let done = !matches!(read, Ok(bytes) if bytes > 0);
+                if sender.send(read.map(|_| part)).await.is_err() || done {
+                    break;
+                }
+            }
+        });
+        let mut writer = self.operator().writer(path).await?;
+        let mut result = Ok(());
+        while let Some(part) = parts.recv().await {
+            result = match part {
+                Ok(part) if part.is_empty() => break,
+                Ok(part) => writer.append(part).await.map_err(WorkerError::from),
+                Err(e) => Err(e.into()),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        // Stops the reader if it is still going
+        drop(parts);
+        let _ = reader.await;
+        match result {
+            Ok(()) => writer.close().await?,
+            Err(e) => {
+                if let Err(abort) = writer.abort().await {
+                    warn!("Unable to abort the upload of {}: {}", path, abort);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Store a symbolic link as an object holding its target, tagged so discovery recognizes it
+    async fn put_symlink(&self, path: &str, target: &[u8]) -> WorkerResult<()> {
+        let args = OpWrite::new().with_content_type(SYMLINK_CONTENT_TYPE);
+        self.operator().write_with(path, args, target.to_vec()).await?;
+        Ok(())
+    }
+
+    // Write the zero-byte "path/" marker that keeps an empty directory listed
+    async fn create_dir(&self, path: &str) -> WorkerResult<()> {
+        self.operator().create_dir(path).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> WorkerResult<()> {
+        self.operator().delete(path).await?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> WorkerResult<()> {
+        copy_object(self.operator(), from, to).await?;
+        Ok(())
+    }
+
+    // Object stores have no rename, the object is copied to its new key and the old one deleted
+    async fn rename_object(&self, from: &str, to: &str) -> WorkerResult<()> {
+        copy_object(self.operator(), from, to).await?;
+        self.operator().delete(from).await?;
+        Ok(())
+    }
 
-    pub async fn list_dir(&self, path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>>{
-        let op = Operator::new(self.builder.clone())?.finish();
-        let mut ds = op.list(path).await?;
+    // Move every object under the prefix `from` ("a/b/") to `to`, including the directory marker
+    async fn rename_dir(&self, from: &str, to: &str) -> WorkerResult<()> {
+        let mut objects = self.operator().scan(from).await?;
+        let mut keys = Vec::new();
+        while let Some(entry) = objects.try_next().await? {
+            keys.push(entry.path().to_string());
+        }
+        // The marker only matters for an empty directory, anything else under the prefix keeps it
+        // listed. Backends that refuse markers keep a directory with an object of its own.
+        if let Err(e) = self.operator().create_dir(to).await {
+            if keys.is_empty() {
+                return Err(e.into());
+            }
+        }
+        for key in &keys {
+            let target = format!("{}{}", to, &key[from.len()..]);
+            if key.ends_with('/') {
+                self.operator().create_dir(&target).await?;
+            } else {
+                copy_object(self.operator(), key, &target).await?;
+            }
+        }
+        // Only delete once everything was copied, a failure midway leaves both trees complete
+        for key in keys.iter().rev() {
+            self.operator().delete(key).await?;
+        }
+        self.operator().delete(from).await?;
+        Ok(())
+    }
+
+    async fn list_dir(&self, path: &str) -> WorkerResult<Vec<String>>{
+        let mut ds = self.operator().list(path).await?;
         let mut filenames = Vec::new();
 
         while let Some(de) = ds.try_next().await? {
@@ -175,4 +577,416 @@ impl GcsWorker {
         }
         Ok(filenames)
     }
-}
\ No newline at end of file
+}
+
+#[async_trait]
+impl StorageBackend for GcsWorker {
+    fn operator(&self) -> &Operator {
+        &self.op
+    }
+
+    fn download_options(&self) -> DownloadOptions {
+        self.download
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Worker {
+    fn operator(&self) -> &Operator {
+        &self.op
+    }
+
+    fn download_options(&self) -> DownloadOptions {
+        self.download
+    }
+}
+
+// Fetch `size` bytes of an object into a local file in parallel blocks. Returns the bytes
+// delivered per block, a block ends early when the backend has no more data for it. Blocks are
+// read through the backend rather than its operator, so wrappers and test doubles see them.
+async fn download_blocks<B: StorageBackend + ?Sized>(
+    backend: &B,
+    path: &str,
+    local_file_path: &str,
+    size: u64,
+    options: DownloadOptions,
+) -> WorkerResult<Vec<u64>> {
+    // Create and initialize the file
+    let file = File::create(local_file_path).await?;
+    file.set_len(size).await?;
+    let file = Mutex::new(file);
+    let block_size = options.block_size;
+    let num_blocks = (size as f64 / block_size as f64).ceil() as u64;
+    // Up to `threads` requests are in flight at once, delivered in block order
+    futures::stream::iter(0..num_blocks.max(1))
+        .map(|i| {
+            let start = block_size * i;
+            fetch_block(backend, path, &file, start..min(start + block_size, size))
+        })
+        .buffered(options.threads)
+        .try_collect()
+        .await
+}
+
+// Write one block of an object into the file at the same offset and return the bytes delivered.
+// A backend may answer a range request short, so the remainder is requested until the whole
+// block is written, rather than leaving a hole.
+async fn fetch_block<B: StorageBackend + ?Sized>(
+    backend: &B,
+    path: &str,
+    file: &Mutex<File>,
+    range: Range<u64>,
+) -> WorkerResult<u64> {
+    let mut offset = range.start;
+    while offset < range.end {
+        let data = backend.read_range(path, offset, range.end - offset).await?;
+        if data.is_empty() {
+            // The object is shorter than its stat said
+            break;
+        }
+        let len = std::cmp::min(data.len() as u64, range.end - offset);
+        let mut file = file.lock().await;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(&data[..len as usize]).await?;
+        offset += len;
+    }
+    Ok(offset - range.start)
+}
+
+
+// Server-side copy where the service supports it, otherwise through this process
+async fn copy_object(op: &Operator, from: &str, to: &str) -> Result<(), opendal::Error> {
+    match op.copy(from, to).await {
+        Err(e) if e.kind() == ErrorKind::Unsupported => {
+            let data = op.read(from).await?;
+            op.write(to, data).await
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use opendal::services::Memory;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Objects kept in memory, uploads take the same code path as on the real services. Clones
+    // share the objects.
+    #[derive(Clone)]
+    pub(crate) struct MemoryBackend {
+        op: Operator,
+        options: DownloadOptions,
+        // Fail creating zero-byte "dir/" markers, as some S3 compatible services do
+        pub(crate) refuse_markers: bool,
+        // Answer range requests with at most this many bytes, as backends may
+        pub(crate) short_reads: Option<u64>,
+        // Content-Length stat reports for every object, whatever its contents
+        pub(crate) reported_size: Option<u64>,
+    }
+
+    impl MemoryBackend {
+        pub(crate) fn new(options: DownloadOptions) -> MemoryBackend {
+            MemoryBackend {
+                op: Operator::new(Memory::default()).unwrap().finish(),
+                options,
+                refuse_markers: false,
+                short_reads: None,
+                reported_size: None,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for MemoryBackend {
+        fn operator(&self) -> &Operator {
+            &self.op
+        }
+
+        fn download_options(&self) -> DownloadOptions {
+            self.options
+        }
+
+        async fn create_dir(&self, path: &str) -> WorkerResult<()> {
+            if self.refuse_markers {
+                return Err(WorkerError::Other(format!("{}: directory markers are not supported", path)));
+            }
+            self.op.create_dir(path).await?;
+            Ok(())
+        }
+
+        async fn get_stats(&self, path: &str) -> WorkerResult<Metadata> {
+            let metadata = self.op.stat(path).await?;
+            Ok(match self.reported_size {
+                Some(size) => metadata.with_content_length(size),
+                None => metadata,
+            })
+        }
+
+        async fn read_range(&self, path: &str, offset: u64, len: u64) -> WorkerResult<Vec<u8>> {
+            let len = self.short_reads.map_or(len, |short| len.min(short));
+            Ok(self.op.range_read(path, offset..offset + len).await?)
+        }
+    }
+
+    fn http_options() -> HttpOptions {
+        HttpOptions {
+            max_idle_per_host: 1,
+            idle_timeout: Duration::from_secs(1),
+            tcp_keepalive: Duration::from_secs(1),
+            connect_timeout: None,
+            http2_only: false,
+            ca_cert: None,
+            insecure_skip_verify: false,
+        }
+    }
+
+    #[test]
+    fn client_requests_identity_encoding() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/object", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = stream.read(&mut buf).unwrap();
+                assert!(read > 0, "connection closed before the request headers ended");
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+
+        let client = http_options().client_builder().build().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let status = rt.block_on(client.get(&url).send()).unwrap().status();
+        assert_eq!(status, 200);
+        let request = server.join().unwrap();
+        assert!(request.contains("\r\naccept-encoding: identity\r\n"), "{}", request);
+    }
+
+    // Answer every request with an empty 200 on connections kept open, counting the connections
+    fn serve_keep_alive(listener: TcpListener) -> Arc<AtomicUsize> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    loop {
+                        let read = match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => read,
+                        };
+                        request.extend_from_slice(&buf[..read]);
+                        while let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                            request.drain(..end + 4);
+                            if stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        connections
+    }
+
+    #[test]
+    fn sequential_requests_reuse_a_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/object", listener.local_addr().unwrap());
+        let connections = serve_keep_alive(listener);
+        let client = http_options().client_builder().build().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            for _ in 0..3 {
+                let response = client.get(&url).send().await.unwrap();
+                assert_eq!(response.status(), 200);
+                response.bytes().await.unwrap();
+                // The connection goes back to the pool once its task sees the response finished
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    // Self-signed, valid until 2126
+    const TEST_CA_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBkTCCATegAwIBAgIUZ0OWMpmrSfv24dfQAR5vIibWZl0wCgYIKoZIzj0EAwIw
+HTEbMBkGA1UEAwwScnVzdHktczNmcyB0ZXN0IENBMCAXDTI2MTAxNjEwMDcwNFoY
+DzIxMjYwOTIyMTAwNzA0WjAdMRswGQYDVQQDDBJydXN0eS1zM2ZzIHRlc3QgQ0Ew
+WTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAST3HYeuumbph2nFbiNwFUS83+TspLM
+/KOAYoTBeJfdFFutSMcnZCukiIq32RejmgH9rmz6uQyqSmwb0scu2c9ko1MwUTAd
+BgNVHQ4EFgQUwrY64H715+PVoI6Uo3HYCpSjHckwHwYDVR0jBBgwFoAUwrY64H71
+5+PVoI6Uo3HYCpSjHckwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBF
+AiEAw6ZlcZCLF59oRjHpQjwQJr+5LPyvoxkGrTVk+12nzVcCIDn2thXI4O7yTAy0
+BcdMuLM5wVvGS04clj1oCBGInWUn
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn ca_certificates_are_checked_when_loaded() {
+        let path = std::env::temp_dir().join(format!("rusty-s3fs-ca-{}.pem", std::process::id()));
+        let load = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            load_ca_cert(path.to_str().unwrap())
+        };
+        let cert = load(TEST_CA_PEM).unwrap();
+        assert!(load("not a certificate\n").is_err());
+        assert!(load(&TEST_CA_PEM.replace("MIIB", "!!!!")).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_ca_cert(path.to_str().unwrap()).is_err());
+
+        // The worker's client is built trusting it
+        let http = HttpOptions {
+            ca_cert: Some(cert),
+            ..http_options()
+        };
+        assert!(http.client_builder().build().is_ok());
+        S3Worker::with_config(
+            "bucket".to_string(),
+            http,
+            DownloadOptions::default(),
+            Some("https://127.0.0.1:1"),
+            Some("us-east-1"),
+        );
+    }
+
+    // A socket that never accepts, with a backlog so full that further handshakes go unanswered
+    fn stalled_endpoint() -> (TcpListener, Vec<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+        let address = listener.local_addr().unwrap();
+        let mut pending = Vec::new();
+        while let Ok(stream) = TcpStream::connect_timeout(&address, Duration::from_millis(100)) {
+            pending.push(stream);
+            assert!(pending.len() < 64, "the backlog never filled up");
+        }
+        (listener, pending)
+    }
+
+    #[test]
+    fn connecting_to_a_dead_endpoint_gives_up_at_the_connect_timeout() {
+        let http = HttpOptions {
+            connect_timeout: Some(Duration::from_millis(200)),
+            ..http_options()
+        };
+        let client = http.client_builder().build().unwrap();
+        let (listener, _pending) = stalled_endpoint();
+        let url = format!("http://{}/object", listener.local_addr().unwrap());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let started = std::time::Instant::now();
+        let error = rt.block_on(client.get(&url).send()).unwrap_err();
+        assert!(error.is_connect() || error.is_timeout(), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn backend_errors_map_to_an_errno() {
+        let classify = |error: opendal::Error| {
+            let error = WorkerError::from(error);
+            let errno = error.errno();
+            (error, errno)
+        };
+        let error = |kind| opendal::Error::new(kind, "key");
+        assert!(matches!(classify(error(ErrorKind::NotFound)), (WorkerError::NotFound(_), libc::ENOENT)));
+        assert!(matches!(classify(error(ErrorKind::PermissionDenied)), (WorkerError::PermissionDenied(_), libc::EACCES)));
+        assert!(matches!(classify(error(ErrorKind::RateLimited)), (WorkerError::RateLimited(_), libc::EAGAIN)));
+        // Throttling stays throttling when the service also marks it as worth retrying
+        assert!(matches!(
+            classify(error(ErrorKind::RateLimited).set_temporary()),
+            (WorkerError::RateLimited(_), libc::EAGAIN)
+        ));
+        assert!(matches!(classify(error(ErrorKind::Unexpected).set_temporary()), (WorkerError::Network(_), libc::EIO)));
+        for kind in [
+            ErrorKind::Unexpected,
+            ErrorKind::Unsupported,
+            ErrorKind::ConfigInvalid,
+            ErrorKind::IsADirectory,
+            ErrorKind::NotADirectory,
+            ErrorKind::AlreadyExists,
+            ErrorKind::IsSameFile,
+        ] {
+            assert!(matches!(classify(error(kind)), (WorkerError::Other(_), libc::EIO)), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn part_size_scales_to_the_part_limit() {
+        const MIB: u64 = 1024 * 1024;
+        // Fits as configured
+        assert_eq!(scaled_part_size(640 * MIB, 64 * MIB, 10_000).unwrap(), 64 * MIB);
+        assert_eq!(scaled_part_size(10_000 * 64 * MIB, 64 * MIB, 10_000).unwrap(), 64 * MIB);
+        // One byte over takes the next whole MiB
+        let size = 10_000 * 64 * MIB + 1;
+        let part_size = scaled_part_size(size, 64 * MIB, 10_000).unwrap();
+        assert_eq!(part_size, 65 * MIB);
+        assert!((size - 1) / part_size < 10_000);
+        let size = 3 * 1024 * 1024 * MIB;
+        let part_size = scaled_part_size(size, 5 * MIB, 1000).unwrap();
+        assert!((size - 1) / part_size < 1000);
+        // Even the largest parts are too small
+        assert!(scaled_part_size(10_000 * MAX_UPLOAD_PART_SIZE + 1, 64 * MIB, 10_000).is_err());
+    }
+
+    #[test]
+    fn put_file_uploads_exactly_size_bytes() {
+        let local = std::env::temp_dir().join(format!("rusty-s3fs-put-file-{}", std::process::id()));
+        std::fs::write(&local, b"0123456789").unwrap();
+        let local = local.to_str().unwrap().to_string();
+        let backend = MemoryBackend::new(DownloadOptions {
+            upload_part_size: 4,
+            ..DownloadOptions::default()
+        });
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let upload = |key: &str, size: u64| {
+            rt.block_on(backend.put_file(key, &local, size, &ObjectHeaders::default())).unwrap();
+            rt.block_on(backend.op.read(key)).unwrap()
+        };
+        // Single requests and multipart uploads alike stop at `size`
+        assert_eq!(upload("single", 3), b"012");
+        assert_eq!(upload("parts", 7), b"0123456");
+        assert_eq!(upload("whole", 10), b"0123456789");
+        // and pad a shorter file with zeros
+        assert_eq!(upload("padded", 13), b"0123456789\0\0\0");
+        assert_eq!(upload("empty", 0), b"");
+        std::fs::remove_file(&local).unwrap();
+    }
+
+    #[test]
+    fn short_range_reads_are_completed() {
+        let options = DownloadOptions { block_size: 16, threads: 4, ..DownloadOptions::default() };
+        let backend = MemoryBackend { short_reads: Some(5), ..MemoryBackend::new(options) };
+        let data: Vec<u8> = (0..100).collect();
+        let local = std::env::temp_dir().join(format!("rusty-s3fs-short-reads-{}", std::process::id()));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(backend.put_data("object", data.clone())).unwrap();
+        let size = rt.block_on(backend.get_data("object", local.to_str().unwrap())).unwrap();
+        assert_eq!(size, 100);
+        assert_eq!(std::fs::read(&local).unwrap(), data);
+        std::fs::remove_file(&local).unwrap();
+    }
+
+    #[test]
+    fn downloads_keep_the_bytes_delivered_when_stat_reports_more() {
+        let options = DownloadOptions { block_size: 16, threads: 4, ..DownloadOptions::default() };
+        let backend = MemoryBackend { reported_size: Some(40), ..MemoryBackend::new(options) };
+        let data: Vec<u8> = (0..25).collect();
+        let local = std::env::temp_dir().join(format!("rusty-s3fs-reported-size-{}", std::process::id()));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(backend.put_data("object", data.clone())).unwrap();
+        let size = rt.block_on(backend.get_data("object", local.to_str().unwrap())).unwrap();
+        assert_eq!(size, 25);
+        assert_eq!(std::fs::read(&local).unwrap(), data);
+        std::fs::remove_file(&local).unwrap();
+    }
+}
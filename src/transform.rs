@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use log::debug;
+use opendal::{Metadata, Operator};
+use crate::s3util::{DownloadOptions, ObjectHeaders, StorageBackend, WorkerResult};
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    // Matches the text of one path component up to the literal that follows it
+    Placeholder(String),
+}
+
+enum Matched<'a> {
+    // The whole template matched the start of the path, with the values of its placeholders
+    // and where the rest of the path starts
+    Complete(HashMap<&'a str, &'a str>, usize),
+    // The path ended inside the template after at least one placeholder
+    Partial,
+    None,
+}
+
+// Presents backend key prefixes under different paths, e.g. the partitions
+// "logs/{yyyy}/{mm}/{dd}/" as "logs/{yyyy}-{mm}-{dd}/". Paths are mapped by their leading
+// template match and the rest of the key is kept as is.
+#[derive(Clone)]
+pub(crate) struct PrefixTransform {
+    backend: Vec<Token>,
+    display: Vec<Token>,
+}
+
+impl PrefixTransform {
+    // Parse "BACKEND=DISPLAY". Both sides are prefixes ending in "/" with the same {name}
+    // placeholders, each followed by literal text. The display side keeps its placeholders in
+    // its last path component, so a listed partition is a single directory.
+    pub fn parse(spec: &str) -> Result<PrefixTransform, String> {
+        let (backend, display) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("{}: expected BACKEND=DISPLAY", spec))?;
+        let transform = PrefixTransform {
+            backend: parse_template(backend)?,
+            display: parse_template(display)?,
+        };
+        let mut backend_names = placeholder_names(&transform.backend);
+        let mut display_names = placeholder_names(&transform.display);
+        backend_names.sort_unstable();
+        display_names.sort_unstable();
+        if backend_names.is_empty() || backend_names != display_names {
+            return Err(format!("{}: both sides need the same placeholders", spec));
+        }
+        // From the first placeholder on, only the final "/" may end a path component
+        let first = transform.display.iter().position(|token| matches!(token, Token::Placeholder(_))).unwrap_or(0);
+        let component = transform.display[first..].iter().filter_map(|token| match token {
+            Token::Literal(text) => Some(text.as_str()),
+            Token::Placeholder(_) => None,
+        });
+        if component.collect::<String>().strip_suffix('/').unwrap_or_default().contains('/') {
+            return Err(format!("{}: the display placeholders must be in one path component", display));
+        }
+        Ok(transform)
+    }
+
+    pub fn to_display(&self, key: &str) -> String {
+        map_prefix(&self.backend, &self.display, key)
+    }
+
+    pub fn to_backend(&self, path: &str) -> String {
+        map_prefix(&self.display, &self.backend, path)
+    }
+
+    // A prefix that only gets a display path once listed further, e.g. "logs/2024/"
+    fn is_partial(&self, key: &str) -> bool {
+        matches!(match_template(&self.backend, key), Matched::Partial)
+    }
+
+    // A path that would be taken for a mapped one, which the backend key itself can't be shown as
+    fn shadowed(&self, key: &str) -> bool {
+        matches!(match_template(&self.display, key), Matched::Complete(..))
+    }
+}
+
+fn parse_template(template: &str) -> Result<Vec<Token>, String> {
+    if !template.ends_with('/') {
+        return Err(format!("{}: a prefix template must end with /", template));
+    }
+    let mut tokens = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format!("{}: unclosed placeholder", template))?;
+        let name = &rest[start + 1..end];
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("{}: invalid placeholder {{{}}}", template, name));
+        }
+        if start > 0 {
+            tokens.push(Token::Literal(rest[..start].to_string()));
+        } else if matches!(tokens.last(), Some(Token::Placeholder(_))) {
+            return Err(format!("{}: placeholders must be separated by text", template));
+        }
+        tokens.push(Token::Placeholder(name.to_string()));
+        rest = &rest[end + 1..];
+    }
+    tokens.push(Token::Literal(rest.to_string()));
+    let names = placeholder_names(&tokens);
+    if (1..names.len()).any(|i| names[..i].contains(&names[i])) {
+        return Err(format!("{}: a placeholder is used twice", template));
+    }
+    Ok(tokens)
+}
+
+fn placeholder_names(tokens: &[Token]) -> Vec<&str> {
+    tokens
+        .iter()
+        .filter_map(|token| match token {
+            Token::Placeholder(name) => Some(name.as_str()),
+            Token::Literal(_) => None,
+        })
+        .collect()
+}
+
+fn match_template<'a>(tokens: &'a [Token], path: &'a str) -> Matched<'a> {
+    let mut values = HashMap::new();
+    let mut position = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        if position == path.len() {
+            return if values.is_empty() { Matched::None } else { Matched::Partial };
+        }
+        match token {
+            Token::Literal(text) => {
+                if !path[position..].starts_with(text.as_str()) {
+                    return Matched::None;
+                }
+                position += text.len();
+            }
+            Token::Placeholder(name) => {
+                // parse_template puts literal text after every placeholder
+                let next = match &tokens[index + 1] {
+                    Token::Literal(text) => text,
+                    Token::Placeholder(_) => return Matched::None,
+                };
+                // The shortest non-empty value, which can't span path components
+                let first_char = path[position..].chars().next().map_or(0, char::len_utf8);
+                let length = match path[position + first_char..].find(next.as_str()) {
+                    Some(found) => first_char + found,
+                    None => return Matched::None,
+                };
+                let value = &path[position..position + length];
+                if value.contains('/') {
+                    return Matched::None;
+                }
+                values.insert(name.as_str(), value);
+                position += length;
+            }
+        }
+    }
+    Matched::Complete(values, position)
+}
+
+fn map_prefix(from: &[Token], to: &[Token], path: &str) -> String {
+    let (values, end) = match match_template(from, path) {
+        Matched::Complete(values, end) => (values, end),
+        _ => return path.to_string(),
+    };
+    let mut mapped = String::new();
+    for token in to {
+        match token {
+            Token::Literal(text) => mapped.push_str(text),
+            Token::Placeholder(name) => mapped.push_str(values[name.as_str()]),
+        }
+    }
+    mapped.push_str(&path[end..]);
+    mapped
+}
+
+// A backend whose keys are presented through a PrefixTransform. The filesystem only sees display
+// paths, every request maps them to backend keys on the way in and listings map them back.
+pub(crate) struct TransformedBackend {
+    inner: Box<dyn StorageBackend>,
+    transform: PrefixTransform,
+}
+
+impl TransformedBackend {
+    pub fn new(inner: Box<dyn StorageBackend>, transform: PrefixTransform) -> TransformedBackend {
+        TransformedBackend { inner, transform }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for TransformedBackend {
+    fn operator(&self) -> &Operator {
+        self.inner.operator()
+    }
+
+    fn download_options(&self) -> DownloadOptions {
+        self.inner.download_options()
+    }
+
+    fn object_key(&self, path: &str) -> String {
+        self.inner.object_key(&self.transform.to_backend(path))
+    }
+
+    async fn is_exist(&self, path: &str) -> WorkerResult<bool> {
+        self.inner.is_exist(&self.transform.to_backend(path)).await
+    }
+
+    async fn get_stats(&self, path: &str) -> WorkerResult<Metadata> {
+        self.inner.get_stats(&self.transform.to_backend(path)).await
+    }
+
+    async fn get_data(&self, path: &str, local_file_path: &str) -> WorkerResult<u64> {
+        self.inner.get_data(&self.transform.to_backend(path), local_file_path).await
+    }
+
+    async fn read_range(&self, path: &str, offset: u64, len: u64) -> WorkerResult<Vec<u8>> {
+        self.inner.read_range(&self.transform.to_backend(path), offset, len).await
+    }
+
+    async fn put_data(&self, path: &str, data: Vec<u8>) -> WorkerResult<()> {
+        self.inner.put_data(&self.transform.to_backend(path), data).await
+    }
+
+    async fn put_data_with(&self, path: &str, data: Vec<u8>, headers: &ObjectHeaders) -> WorkerResult<()> {
+        self.inner.put_data_with(&self.transform.to_backend(path), data, headers).await
+    }
+
+    async fn put_file(&self, path: &str, local_file_path: &str, size: u64, headers: &ObjectHeaders) -> WorkerResult<()> {
+        self.inner
+            .put_file(&self.transform.to_backend(path), local_file_path, size, headers)
+            .await
+    }
+
+    async fn put_symlink(&self, path: &str, target: &[u8]) -> WorkerResult<()> {
+        self.inner.put_symlink(&self.transform.to_backend(path), target).await
+    }
+
+    async fn create_dir(&self, path: &str) -> WorkerResult<()> {
+        self.inner.create_dir(&self.transform.to_backend(path)).await
+    }
+
+    async fn delete(&self, path: &str) -> WorkerResult<()> {
+        self.inner.delete(&self.transform.to_backend(path)).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> WorkerResult<()> {
+        self.inner.copy(&self.transform.to_backend(from), &self.transform.to_backend(to)).await
+    }
+
+    async fn rename_object(&self, from: &str, to: &str) -> WorkerResult<()> {
+        self.inner
+            .rename_object(&self.transform.to_backend(from), &self.transform.to_backend(to))
+            .await
+    }
+
+    async fn rename_dir(&self, from: &str, to: &str) -> WorkerResult<()> {
+        self.inner
+            .rename_dir(&self.transform.to_backend(from), &self.transform.to_backend(to))
+            .await
+    }
+
+    // Prefixes part way through the backend template are listed further down to the complete
+    // partitions, which show up as single directories. Objects beside those prefixes have no
+    // display path and are left out, as are keys that look like a display path themselves.
+    async fn list_dir(&self, path: &str) -> WorkerResult<Vec<String>> {
+        let mut names = Vec::new();
+        let mut prefixes = vec![self.transform.to_backend(path)];
+        while let Some(prefix) = prefixes.pop() {
+            for name in self.inner.list_dir(&prefix).await? {
+                let key = format!("{}{}", prefix, name);
+                if name.ends_with('/') && self.transform.is_partial(&key) {
+                    prefixes.push(key);
+                    continue;
+                }
+                let display = self.transform.to_display(&key);
+                if display == key && self.transform.shadowed(&key) {
+                    debug!("{} looks like a transformed path, it is not listed", key);
+                    continue;
+                }
+                match display.strip_prefix(path) {
+                    Some(name) if !name.trim_end_matches('/').contains('/') => names.push(name.to_string()),
+                    _ => debug!("{} has no path under {}, it is not listed", key, path),
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partitions() -> PrefixTransform {
+        PrefixTransform::parse("logs/{yyyy}/{mm}/{dd}/=logs/{yyyy}-{mm}-{dd}/").unwrap()
+    }
+
+    #[test]
+    fn keys_round_trip_through_display_paths() {
+        let transform = partitions();
+        for (key, path) in [
+            ("logs/2024/01/31/", "logs/2024-01-31/"),
+            ("logs/2024/01/31/app.log", "logs/2024-01-31/app.log"),
+            ("logs/2024/01/31/nested/app.log", "logs/2024-01-31/nested/app.log"),
+        ] {
+            assert_eq!(transform.to_display(key), path);
+            assert_eq!(transform.to_backend(path), key);
+            assert_eq!(transform.to_backend(&transform.to_display(key)), key);
+        }
+    }
+
+    #[test]
+    fn keys_outside_the_template_are_kept() {
+        let transform = partitions();
+        for key in ["other/2024/01/31/app.log", "logs/", "logs/readme.txt", ""] {
+            assert_eq!(transform.to_display(key), key);
+            assert_eq!(transform.to_backend(key), key);
+        }
+        // Listed further before it maps
+        assert!(transform.is_partial("logs/2024/"));
+        assert!(!transform.is_partial("logs/2024/01/31/"));
+        // A backend key that looks like a display path can't be shown as itself
+        assert!(transform.shadowed("logs/2024-01-31/"));
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        for spec in [
+            "logs/{yyyy}/",
+            "logs/{yyyy}/=logs/{mm}/",
+            "logs/{yyyy}=logs/{yyyy}/",
+            "logs/{yyyy}{mm}/=logs/{yyyy}-{mm}/",
+            "logs/{yyyy}/{yyyy}/=logs/{yyyy}-{yyyy}/",
+            "logs/{yyyy}/{mm}/=logs/{yyyy}/{mm}/",
+            "logs/{yyyy/=logs/{yyyy}/",
+        ] {
+            assert!(PrefixTransform::parse(spec).is_err(), "{}", spec);
+        }
+    }
+}